@@ -45,7 +45,7 @@ fn main() -> io::Result<()> {
         y.reverse();
     });
     let mut str = String::from(
-        "#![allow(all)]\n\nuse std::sync::LazyLock;\n\nuse ptrie::Trie;\n\n#[derive(Debug, Clone, Copy)]\npub enum IntToken {",
+        "#![allow(all)]\n\nuse std::sync::LazyLock;\n\nuse ptrie::Trie;\n\n#[derive(Debug, Clone, Copy, PartialEq, Eq)]\npub enum IntToken {",
     );
 
     for (k, _) in &s {
@@ -56,6 +56,16 @@ fn main() -> io::Result<()> {
     }
     str += "\n}";
 
+    str += "\n\nimpl IntToken {\n\tpub fn name(self) -> &'static str {\n\t\tmatch self {";
+    for (k, _) in &s {
+        str += "\n\t\t\tIntToken::";
+        str += &kebab_to_pascal_case(k);
+        str += " => \"";
+        str += &k.replace('-', " ");
+        str += "\",";
+    }
+    str += "\n\t\t}\n\t}\n}";
+
     str += "\n\nstatic TRIE: LazyLock<Trie<u8, (IntToken, usize)>> = LazyLock::new(|| {
 \tlet mut trie = Trie::new();
 ";
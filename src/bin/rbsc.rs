@@ -1,18 +1,209 @@
-use std::fs;
+use std::{
+    env,
+    fmt::Display,
+    io::{self, IsTerminal},
+    path::Path,
+    process,
+};
 
-use rbs::{parse, transpile};
+use rbs::{
+    Diagnostic, RobotConfig, Severity, Sources, Span, check_sensors, parse, parse_with_lints,
+    transpile,
+};
+
+const BOLD: &str = "1";
+const BLUE: &str = "1;34";
+
+struct Renderer<'a> {
+    sources: &'a Sources,
+    lines: Vec<Vec<&'a str>>,
+    color: bool,
+}
+
+impl<'a> Renderer<'a> {
+    fn new(sources: &'a Sources, color: bool) -> Self {
+        let lines = (0..sources.len())
+            .map(|file| sources.get(file).code.lines().collect())
+            .collect();
+        Renderer {
+            sources,
+            lines,
+            color,
+        }
+    }
+
+    fn paint(&self, style: &str, text: impl Display) -> String {
+        if self.color {
+            format!("\x1b[{style}m{text}\x1b[0m")
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn location(&self, span: Span) -> String {
+        let path = self.sources.get(span.file).path.display();
+        format!("{path}:{}:{}", span.line, span.col)
+    }
+
+    fn render(&self, diagnostic: &Diagnostic) -> String {
+        let style = match diagnostic.severity {
+            Severity::Error => "1;31",
+            Severity::Warning => "1;33",
+            Severity::Note => "1;36",
+        };
+        let width = diagnostic
+            .labels
+            .iter()
+            .map(|label| label.span.line)
+            .chain([diagnostic.span.line])
+            .max()
+            .unwrap_or(1)
+            .to_string()
+            .len();
+        let pad = " ".repeat(width);
+
+        let mut out = format!(
+            "{}{}\n",
+            self.paint(
+                style,
+                format!("{}[{}]", diagnostic.severity, diagnostic.code)
+            ),
+            self.paint(BOLD, format!(": {}", diagnostic.message)),
+        );
+        out += &format!(
+            "{pad}{} {}\n",
+            self.paint(BLUE, "-->"),
+            self.location(diagnostic.span)
+        );
+        self.snippet(&mut out, width, diagnostic.span, '^', style, "");
+
+        for label in &diagnostic.labels {
+            if label.span.file != diagnostic.span.file {
+                out += &format!(
+                    "{pad}{} {}\n",
+                    self.paint(BLUE, ":::"),
+                    self.location(label.span)
+                );
+            }
+            self.snippet(&mut out, width, label.span, '-', BLUE, &label.message);
+        }
+
+        if let Some(help) = &diagnostic.help {
+            out += &format!(
+                "{pad} {} {}: {help}\n",
+                self.paint(BLUE, "="),
+                self.paint(BOLD, "help")
+            );
+        }
+        out
+    }
+
+    fn snippet(
+        &self,
+        out: &mut String,
+        width: usize,
+        span: Span,
+        mark: char,
+        style: &str,
+        message: &str,
+    ) {
+        let code = &self.sources.get(span.file).code;
+        let Some(text) = span
+            .line
+            .checked_sub(1)
+            .and_then(|line| self.lines[span.file].get(line).copied())
+        else {
+            return;
+        };
+        let indent = text
+            .chars()
+            .take(span.col.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let len = code
+            .get(span.start..span.end)
+            .and_then(|text| text.lines().next())
+            .map_or(0, |text| text.chars().count())
+            .max(1);
+        let marks = mark.to_string().repeat(len);
+
+        let pad = " ".repeat(width);
+        let bar = self.paint(BLUE, "|");
+        *out += &format!("{pad} {bar}\n");
+        *out += &format!(
+            "{} {bar} {text}\n",
+            self.paint(BLUE, format!("{:>width$}", span.line))
+        );
+        *out += &format!(
+            "{pad} {bar} {indent}{}\n",
+            self.paint(style, format!("{marks} {message}").trim_end())
+        );
+    }
+}
 
 fn main() {
-    let str = fs::read_to_string("code.rbs").unwrap();
-    let (commands, errors) = parse(str);
-    println!("{commands:?}");
-    if !errors.is_empty() {
-        println!("--------");
-        for i in errors {
-            println!("{i}");
+    let mut path = String::from("code.rbs");
+    let mut config_path = None;
+    let mut lint = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--lint" => lint = true,
+            "--config" => config_path = args.next(),
+            _ => path = arg,
         }
-        println!("--------");
     }
-    let py = transpile(commands);
+
+    let mut sources = Sources::new();
+    if let Err(err) = sources.load(&path) {
+        eprintln!("cannot read \"{path}\": {err}");
+        process::exit(1);
+    }
+
+    let dir = Path::new(&path).parent().unwrap_or(Path::new(""));
+    let config_path = config_path.map(Into::into).or_else(|| {
+        ["rbs.toml", "rbs.json"]
+            .into_iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.exists())
+    });
+    let config = match config_path {
+        Some(config_path) => RobotConfig::load(config_path).unwrap_or_else(|err| {
+            eprintln!("{err}");
+            process::exit(1);
+        }),
+        None => RobotConfig::default(),
+    };
+
+    let (program, mut diagnostics) = if lint {
+        parse_with_lints(&mut sources)
+    } else {
+        parse(&mut sources)
+    };
+    println!("{program:?}");
+    diagnostics.extend(check_sensors(&program, &config));
+    let renderer = Renderer::new(&sources, io::stderr().is_terminal());
+    for i in &diagnostics {
+        eprintln!("{}", renderer.render(i));
+    }
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    if errors > 0 {
+        eprintln!(
+            "{}",
+            renderer.paint(
+                "1;31",
+                format!(
+                    "aborting due to {errors} error{}",
+                    if errors == 1 { "" } else { "s" }
+                )
+            )
+        );
+        process::exit(1);
+    }
+    if let Err(err) = config.with_overrides(&program.config).ports.check() {
+        eprintln!("invalid config: {err}");
+        process::exit(1);
+    }
+    let py = transpile(program, &config);
     println!("{py}");
 }
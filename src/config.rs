@@ -0,0 +1,273 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Config, Port};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RobotConfig {
+    pub ports: Ports,
+    pub wheel_circumference: f32,
+    pub track_width: f32,
+    pub max_speed: f32,
+    pub drive_speed: f32,
+    pub arm_speed: f32,
+    pub front_arm_ratio: f32,
+    pub back_arm_ratio: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ports {
+    pub left_motor: Port,
+    pub right_motor: Port,
+    pub front_arm: Port,
+    pub back_arm: Port,
+    pub color_sensor: Option<Port>,
+    pub distance_sensor: Option<Port>,
+    pub force_sensor: Option<Port>,
+}
+
+impl Default for RobotConfig {
+    fn default() -> Self {
+        RobotConfig {
+            ports: Ports::default(),
+            wheel_circumference: 17.5,
+            track_width: 11.2,
+            max_speed: 1110.0,
+            drive_speed: 100.0,
+            arm_speed: 100.0,
+            front_arm_ratio: 1.0,
+            back_arm_ratio: 1.0,
+        }
+    }
+}
+
+impl Default for Ports {
+    fn default() -> Self {
+        Ports {
+            left_motor: Port::C,
+            right_motor: Port::D,
+            front_arm: Port::E,
+            back_arm: Port::F,
+            color_sensor: Some(Port::A),
+            distance_sensor: Some(Port::B),
+            force_sensor: None,
+        }
+    }
+}
+
+impl RobotConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read \"{}\": {err}", path.display()))?;
+        let config = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&text).map_err(|err| err.to_string())
+        } else {
+            toml::from_str(&text).map_err(|err| err.to_string())
+        };
+        config
+            .and_then(|config: RobotConfig| config.check().map(|()| config))
+            .map_err(|err| format!("invalid config \"{}\": {err}", path.display()))
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("wheel_circumference", self.wheel_circumference),
+            ("track_width", self.track_width),
+            ("max_speed", self.max_speed),
+            ("front_arm_ratio", self.front_arm_ratio),
+            ("back_arm_ratio", self.back_arm_ratio),
+        ] {
+            if !(value > 0.0 && value.is_finite()) {
+                return Err(format!("\"{name}\" must be positive, got {value}"));
+            }
+        }
+        for (name, value) in [
+            ("drive_speed", self.drive_speed),
+            ("arm_speed", self.arm_speed),
+        ] {
+            if !(value > 0.0 && value <= 100.0) {
+                return Err(format!(
+                    "\"{name}\" must be above 0% and at most 100%, got {value}%"
+                ));
+            }
+        }
+        self.ports.check()
+    }
+
+    pub fn with_overrides(&self, config: &Config) -> Self {
+        let mut robot = self.clone();
+        let ports = &mut robot.ports;
+        for (port, value) in [
+            (&mut ports.left_motor, config.left_motor),
+            (&mut ports.right_motor, config.right_motor),
+            (&mut ports.front_arm, config.front_arm),
+            (&mut ports.back_arm, config.back_arm),
+        ] {
+            if let Some(value) = value {
+                *port = value;
+            }
+        }
+        for (port, value) in [
+            (&mut ports.color_sensor, config.color_sensor),
+            (&mut ports.distance_sensor, config.distance_sensor),
+            (&mut ports.force_sensor, config.force_sensor),
+        ] {
+            if value.is_some() {
+                *port = value;
+            }
+        }
+        if let Some(circumference) = config.wheel_circumference {
+            robot.wheel_circumference = circumference;
+        }
+        if let Some(track) = config.track_width {
+            robot.track_width = track;
+        }
+        if let Some(speed) = config.max_speed {
+            robot.max_speed = speed;
+        }
+        robot
+    }
+}
+
+impl Ports {
+    pub fn devices(&self) -> [(&'static str, Option<Port>); 7] {
+        [
+            ("left motor", Some(self.left_motor)),
+            ("right motor", Some(self.right_motor)),
+            ("front arm", Some(self.front_arm)),
+            ("back arm", Some(self.back_arm)),
+            ("color sensor", self.color_sensor),
+            ("distance sensor", self.distance_sensor),
+            ("force sensor", self.force_sensor),
+        ]
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        let devices = self.devices();
+        for (i, (first, port)) in devices.iter().enumerate() {
+            let Some(port) = port else {
+                continue;
+            };
+            if let Some((second, _)) = devices[i + 1..]
+                .iter()
+                .find(|(_, other)| *other == Some(*port))
+            {
+                return Err(format!(
+                    "\"{first}\" and \"{second}\" both use port {port:?}"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn ports(&self) -> [(&'static str, Option<Port>); 7] {
+        [
+            ("left motor", self.left_motor),
+            ("right motor", self.right_motor),
+            ("front arm", self.front_arm),
+            ("back arm", self.back_arm),
+            ("color sensor", self.color_sensor),
+            ("distance sensor", self.distance_sensor),
+            ("force sensor", self.force_sensor),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::RobotConfig;
+    use crate::{Config, Port};
+
+    fn load(name: &str, text: &str) -> Result<RobotConfig, String> {
+        let path = env::temp_dir().join(format!("rbs-{}-{name}", process::id()));
+        fs::write(&path, text).unwrap();
+        let config = RobotConfig::load(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn toml_and_json() {
+        let toml = load(
+            "full.toml",
+            "track_width = 12.5\ndrive_speed = 80\n[ports]\nleft_motor = \"A\"\ncolor_sensor = \"C\"\n",
+        )
+        .unwrap();
+        let json = load(
+            "full.json",
+            r#"{"track_width": 12.5, "drive_speed": 80, "ports": {"left_motor": "A", "color_sensor": "C"}}"#,
+        )
+        .unwrap();
+        for config in [toml, json] {
+            assert_eq!(config.track_width, 12.5);
+            assert_eq!(config.drive_speed, 80.0);
+            assert_eq!(config.ports.left_motor, Port::A);
+            assert_eq!(config.ports.color_sensor, Some(Port::C));
+        }
+    }
+
+    #[test]
+    fn partial_file_keeps_defaults() {
+        let config = load("partial.toml", "arm_speed = 50\n").unwrap();
+        let default = RobotConfig::default();
+        assert_eq!(config.arm_speed, 50.0);
+        assert_eq!(config.track_width, default.track_width);
+        assert_eq!(config.ports.right_motor, default.ports.right_motor);
+        assert_eq!(config.ports.force_sensor, None);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        for (name, text) in [
+            ("unknown.toml", "wheel_size = 5\n"),
+            ("unknown-port.toml", "[ports]\ngyro = \"A\"\n"),
+            ("unknown.json", r#"{"wheel_size": 5}"#),
+        ] {
+            let err = load(name, text).unwrap_err();
+            assert!(err.contains("unknown field"), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        for (name, text) in [
+            ("zero-speed.toml", "drive_speed = 0\n"),
+            ("fast-arm.toml", "arm_speed = 150\n"),
+            ("negative-ratio.toml", "front_arm_ratio = -1\n"),
+            ("zero-track.json", r#"{"track_width": 0}"#),
+            ("shared-port.toml", "[ports]\nforce_sensor = \"F\"\n"),
+        ] {
+            assert!(load(name, text).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn source_config_overrides_file() {
+        let file = load(
+            "file.toml",
+            "track_width = 12\nmax_speed = 900\n[ports]\nleft_motor = \"A\"\ncolor_sensor = \"B\"\ndistance_sensor = \"E\"\nfront_arm = \"F\"\nback_arm = \"D\"\nright_motor = \"C\"\n",
+        )
+        .unwrap();
+        let source = Config {
+            track_width: Some(14.0),
+            left_motor: Some(Port::C),
+            right_motor: Some(Port::A),
+            ..Config::default()
+        };
+        let config = file.with_overrides(&source);
+        assert_eq!(config.track_width, 14.0);
+        assert_eq!(config.max_speed, 900.0);
+        assert_eq!(config.ports.left_motor, Port::C);
+        assert_eq!(config.ports.right_motor, Port::A);
+        assert_eq!(config.ports.color_sensor, Some(Port::B));
+        assert_eq!(config.ports.distance_sensor, Some(Port::E));
+    }
+}
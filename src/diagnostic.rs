@@ -0,0 +1,135 @@
+use std::fmt;
+
+use crate::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Code {
+    InvalidToken,
+    InvalidString,
+    InvalidNumber,
+    UnexpectedToken,
+    ExpectedToken,
+    UnitMismatch,
+    InvalidValue,
+    UndefinedProcedure,
+    UndefinedVariable,
+    DuplicateDefinition,
+    Misplaced,
+    Recursion,
+    ArgumentMismatch,
+    Include,
+    MissingPort,
+    NegativeAmount,
+}
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub message: String,
+    pub span: Span,
+    pub labels: Vec<Label>,
+    pub help: Option<String>,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+}
+
+impl Code {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Code::InvalidToken => "E0001",
+            Code::InvalidString => "E0002",
+            Code::InvalidNumber => "E0003",
+            Code::UnexpectedToken => "E0004",
+            Code::ExpectedToken => "E0005",
+            Code::UnitMismatch => "E0006",
+            Code::InvalidValue => "E0007",
+            Code::UndefinedProcedure => "E0008",
+            Code::UndefinedVariable => "E0009",
+            Code::DuplicateDefinition => "E0010",
+            Code::Misplaced => "E0011",
+            Code::Recursion => "E0012",
+            Code::ArgumentMismatch => "E0013",
+            Code::Include => "E0014",
+            Code::MissingPort => "E0015",
+            Code::NegativeAmount => "W0001",
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: Code, span: Span, message: impl ToString) -> Self {
+        Diagnostic {
+            severity,
+            code,
+            message: message.to_string(),
+            span,
+            labels: Vec::new(),
+            help: None,
+        }
+    }
+
+    pub fn error(code: Code, span: Span, message: impl ToString) -> Self {
+        Diagnostic::new(Severity::Error, code, span, message)
+    }
+
+    pub fn warning(code: Code, span: Span, message: impl ToString) -> Self {
+        Diagnostic::new(Severity::Warning, code, span, message)
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl ToString) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.to_string(),
+        });
+        self
+    }
+
+    pub fn with_help(mut self, help: impl ToString) -> Self {
+        self.help = Some(help.to_string());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.severity, self.code, self.message)
+    }
+}
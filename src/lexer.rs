@@ -1,78 +1,133 @@
-use peek_again::{Peekable, PeekableIterator};
-use std::{mem, str::Chars};
+use std::mem;
 
-use crate::keywords::{self, IntToken};
+use crate::{
+    Code, Diagnostic, Span, Spanned,
+    keywords::{self, IntToken},
+};
 
 #[derive(Debug)]
 pub enum Token {
     Lf,
     Number(f32),
     Int(IntToken),
-    Errors(Vec<String>),
+    Ident(String),
+    Str(String),
+    Op(char),
+    Errors(Vec<Diagnostic>),
 }
 
 pub struct Lexer<'src> {
-    src: Peekable<Chars<'src>>,
-    og_src: &'src str,
-    errors: Vec<String>,
+    src: &'src str,
+    pos: usize,
+    line: usize,
+    col: usize,
+    start: Span,
+    parens: usize,
+    errors: Vec<Diagnostic>,
 }
 
 impl<'src> Lexer<'src> {
-    pub fn new(src: &'src str) -> Self {
+    pub fn new(file: usize, src: &'src str) -> Self {
         Lexer {
-            src: src.chars().peek_again(),
-            og_src: src,
+            src,
+            pos: 0,
+            line: 1,
+            col: 1,
+            start: Span {
+                file,
+                ..Span::default()
+            },
+            parens: 0,
             errors: Vec::new(),
         }
     }
 
-    fn add_error(&mut self, error: impl ToString) {
-        self.errors.push(error.to_string());
+    fn span(&self) -> Span {
+        Span {
+            end: self.pos,
+            ..self.start
+        }
+    }
+
+    fn add_error(&mut self, code: Code, error: impl ToString) {
+        self.errors
+            .push(Diagnostic::error(code, self.span(), error));
+    }
+
+    #[inline(always)]
+    fn rest(&self) -> &'src str {
+        &self.src[self.pos..]
     }
 
     #[inline(always)]
     fn next_char(&mut self) -> Option<char> {
-        let c = self.src.next();
-        if c.is_some() {
-            self.og_src = &self.og_src[1..];
+        let c = self.peek_char()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
         }
-        c
+        Some(c)
     }
 
     #[inline(always)]
-    fn peek_char(&mut self) -> Option<char> {
-        self.src.peek().get().copied()
+    fn peek_char(&self) -> Option<char> {
+        self.rest().chars().next()
     }
 
     #[inline(always)]
-    fn at_eof(&mut self) -> bool {
-        self.peek_char().is_none()
+    fn at_eof(&self) -> bool {
+        self.pos >= self.src.len()
     }
 
     fn skip_whitespace(&mut self) {
-        while matches!(self.peek_char(), Some(c) if c.is_whitespace() && c != '\n') {
+        while matches!(self.peek_char(), Some(c) if (c.is_whitespace() && c != '\n') || (c == ',' && self.parens == 0))
+        {
             self.next_char();
         }
     }
 }
 
 impl<'src> Iterator for Lexer<'src> {
-    type Item = Token;
+    type Item = Spanned<Token>;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.skip_whitespace();
-        if self.at_eof() {
+        self.start = Span {
+            start: self.pos,
+            line: self.line,
+            col: self.col,
+            ..self.start
+        };
+        let token = if self.at_eof() {
             if self.errors.is_empty() {
                 return None;
-            } else {
-                return Some(Token::Errors(mem::take(&mut self.errors)));
             }
+            Token::Errors(mem::take(&mut self.errors))
+        } else {
+            self.token()
+        };
+        match token {
+            Token::Op('(') => self.parens += 1,
+            Token::Op(')') => self.parens = self.parens.saturating_sub(1),
+            Token::Lf => self.parens = 0,
+            _ => {}
         }
+        Some(Spanned {
+            node: token,
+            span: self.span(),
+        })
+    }
+}
 
+impl Lexer<'_> {
+    fn token(&mut self) -> Token {
         match self.peek_char().unwrap() {
-            '\n' => {
+            '\n' | ';' => {
                 self.next_char();
-                Some(Token::Lf)
+                Token::Lf
             }
             '#' => {
                 self.next_char();
@@ -80,17 +135,47 @@ impl<'src> Iterator for Lexer<'src> {
                     self.next_char();
                 }
                 self.next_char();
-                Some(Token::Lf)
+                Token::Lf
             }
-            ',' => {
+            '"' => {
                 self.next_char();
-                self.next()
+                let mut string = String::new();
+                loop {
+                    match self.next_char() {
+                        Some('"') => return Token::Str(string),
+                        Some('\\') => match self.next_char() {
+                            Some('n') => string.push('\n'),
+                            Some('t') => string.push('\t'),
+                            Some(c @ ('"' | '\\')) => string.push(c),
+                            Some(c) if c != '\n' => {
+                                self.add_error(
+                                    Code::InvalidString,
+                                    format!("unknown escape \"\\{c}\" in string"),
+                                );
+                                string.push(c);
+                            }
+                            _ => break,
+                        },
+                        Some(c) if c != '\n' => string.push(c),
+                        _ => break,
+                    }
+                }
+                self.add_error(
+                    Code::InvalidString,
+                    format!("unterminated string \"{string}"),
+                );
+                Token::Str(string)
+            }
+            c @ ('+' | '-' | '*' | '/' | '(' | ')' | ',' | '=' | '<' | '>') => {
+                self.next_char();
+                Token::Op(c)
             }
             c if c.is_numeric() => {
                 self.next_char();
                 let mut ident = String::from(c);
                 while let Some(c) = self.peek_char() {
-                    if c.is_numeric() || c == '.' || c.eq_ignore_ascii_case(&'e') {
+                    let exponent = ident.ends_with(['e', 'E']) && (c == '-' || c == '+');
+                    if c.is_numeric() || c == '.' || c.eq_ignore_ascii_case(&'e') || exponent {
                         ident.push(c);
                     } else {
                         break;
@@ -99,36 +184,164 @@ impl<'src> Iterator for Lexer<'src> {
                 }
 
                 if let Ok(n) = ident.parse::<f32>() {
-                    Some(Token::Number(n))
+                    Token::Number(n)
                 } else {
-                    self.add_error(format!("could not parse number {ident}"));
+                    self.add_error(
+                        Code::InvalidNumber,
+                        format!("could not parse number {ident}"),
+                    );
                     while matches!(self.peek_char(), Some(c) if c != '\n') {
                         self.next_char();
                     }
                     self.next_char();
-                    Some(Token::Lf)
+                    Token::Lf
                 }
             }
-            _ => {
-                let (token, skip) = if let Some((token, skip)) = keywords::make_token(self.og_src) {
+            c => {
+                let rest = self.rest();
+                let keyword = keywords::make_token(rest).filter(|(_, skip)| {
+                    !matches!(rest[*skip..].chars().next(), Some(c) if is_ident_char(c))
+                });
+                let (token, skip) = if let Some((token, skip)) = keyword {
                     (token, skip)
+                } else if c.is_alphabetic() || c == '_' {
+                    let mut ident = String::new();
+                    while matches!(self.peek_char(), Some(c) if is_ident_char(c)) {
+                        ident.push(self.next_char().unwrap());
+                    }
+                    return Token::Ident(ident);
                 } else {
                     let mut string = String::new();
                     while matches!(self.peek_char(), Some(c) if c != '\n') {
                         string.push(self.next_char().unwrap());
                     }
-                    self.add_error(format!("could not parse \"{string}\". ignoring"));
+                    self.add_error(
+                        Code::InvalidToken,
+                        format!("could not parse \"{string}\". ignoring"),
+                    );
                     self.next_char();
-                    return Some(Token::Lf);
+                    return Token::Lf;
                 };
-                self.src.nth(skip - 1);
-                self.og_src = &self.og_src[skip..];
+                let end = self.pos + skip;
+                while self.pos < end {
+                    self.next_char();
+                }
 
                 match token {
-                    IntToken::Then => Some(Token::Lf),
-                    tok => Some(Token::Int(tok)),
+                    IntToken::Then => Token::Lf,
+                    tok => Token::Int(tok),
                 }
             }
         }
     }
 }
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphabetic() || c.is_ascii_digit() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lexer, Token};
+    use crate::keywords::IntToken;
+
+    fn lex(src: &str) -> Vec<Token> {
+        Lexer::new(0, src).map(|token| token.node).collect()
+    }
+
+    #[test]
+    fn keyword_prefix_of_identifier() {
+        let tokens = lex("let s1 = 5\nm2");
+        assert!(matches!(
+            &tokens[..],
+            [
+                Token::Int(IntToken::Let),
+                Token::Ident(s1),
+                Token::Op('='),
+                Token::Number(5.0),
+                Token::Lf,
+                Token::Ident(m2),
+            ] if s1 == "s1" && m2 == "m2"
+        ));
+    }
+
+    #[test]
+    fn identifier_round_trips() {
+        for name in ["s1", "m2", "deg_s", "cm3x", "speed_2"] {
+            let tokens = lex(name);
+            assert!(
+                matches!(&tokens[..], [Token::Ident(ident)] if ident == name),
+                "{name}: {tokens:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn non_ascii_comments_and_strings() {
+        let tokens = lex("# Größe über alles 🤖\nshow \"grüß dich 👋\"\n# 🚀");
+        assert!(
+            matches!(
+                &tokens[..],
+                [
+                    Token::Lf,
+                    Token::Int(IntToken::Show),
+                    Token::Str(text),
+                    Token::Lf,
+                    Token::Lf,
+                ] if text == "grüß dich 👋"
+            ),
+            "{tokens:?}"
+        );
+    }
+
+    #[test]
+    fn degree_sign() {
+        let tokens = lex("90°\n30°/s");
+        assert!(
+            matches!(
+                &tokens[..],
+                [
+                    Token::Number(90.0),
+                    Token::Int(IntToken::Deg),
+                    Token::Lf,
+                    Token::Number(30.0),
+                    Token::Int(IntToken::Dps),
+                ]
+            ),
+            "{tokens:?}"
+        );
+    }
+
+    #[test]
+    fn comma_separates_only_inside_parentheses() {
+        let tokens = lex("f(1, -2)\nwait 1, 2");
+        assert!(
+            matches!(
+                &tokens[..],
+                [
+                    Token::Ident(_),
+                    Token::Op('('),
+                    Token::Number(1.0),
+                    Token::Op(','),
+                    Token::Op('-'),
+                    Token::Number(2.0),
+                    Token::Op(')'),
+                    Token::Lf,
+                    Token::Int(IntToken::Wait),
+                    Token::Number(1.0),
+                    Token::Number(2.0),
+                ]
+            ),
+            "{tokens:?}"
+        );
+    }
+
+    #[test]
+    fn non_ascii_digit_in_identifier_is_rejected() {
+        let tokens = lex("x²");
+        assert!(
+            matches!(tokens.last(), Some(Token::Errors(_))),
+            "{tokens:?}"
+        );
+    }
+}
@@ -1,109 +1,1074 @@
+use std::{collections::BTreeSet, fmt};
+
+use serde::{Deserialize, Serialize};
+
 use crate::parser::Parser;
 
+pub use crate::{
+    config::{Ports, RobotConfig},
+    diagnostic::{Code, Diagnostic, Label, Severity},
+    source::{Source, Sources},
+};
+
 #[rustfmt::skip]
 mod keywords;
 
+mod config;
+mod diagnostic;
 mod lexer;
 mod parser;
+mod source;
+
+pub fn parse(sources: &mut Sources) -> (Program, Vec<Diagnostic>) {
+    parse_inner(sources, false)
+}
+
+pub fn parse_with_lints(sources: &mut Sources) -> (Program, Vec<Diagnostic>) {
+    parse_inner(sources, true)
+}
 
-pub fn parse(code: String) -> (Vec<Command>, Vec<String>) {
-    let mut parser = Parser::new(&code);
-    let mut commands = Vec::new();
-    while let Some(command) = parser.command() {
-        commands.push(command)
+fn parse_inner(sources: &mut Sources, lints: bool) -> (Program, Vec<Diagnostic>) {
+    let mut diagnostics = sources.resolve_includes();
+    let mut parser = Parser::new(sources, lints);
+    let mut script = Vec::new();
+    while let Some(statement) = parser.command() {
+        script.push(statement)
     }
 
-    (commands, parser.finish())
+    let (program, mut parsed) = parser.finish(script);
+    diagnostics.append(&mut parsed);
+    (program, diagnostics)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub file: usize,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    pub fn to(self, end: Span) -> Span {
+        Span {
+            end: end.end.max(self.end),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub config: Config,
+    pub procedures: Vec<Procedure>,
+    pub missions: Vec<Mission>,
+    pub script: Vec<Spanned<Statement>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub left_motor: Option<Port>,
+    pub right_motor: Option<Port>,
+    pub front_arm: Option<Port>,
+    pub back_arm: Option<Port>,
+    pub color_sensor: Option<Port>,
+    pub distance_sensor: Option<Port>,
+    pub force_sensor: Option<Port>,
+    pub wheel_circumference: Option<f32>,
+    pub track_width: Option<f32>,
+    pub max_speed: Option<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Procedure {
+    pub name: String,
+    pub params: Vec<Parameter>,
+    pub span: Span,
+    pub body: Vec<Spanned<Statement>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Parameter {
+    pub name: String,
+    pub dimension: Option<Dimension>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mission {
+    pub name: String,
+    pub body: Vec<Spanned<Statement>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Statement {
+    Command(Command),
+    Repeat(Expr, Vec<Spanned<Statement>>),
+    Call(String, Vec<Expr>),
+    Let(String, Expr),
+    Together(Vec<Command>),
+    If(Condition, Vec<Spanned<Statement>>, Vec<Spanned<Statement>>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Color(Option<Port>, Color),
+    Distance(Option<Port>, Comparison, Quantity),
+    Force(Option<Port>, Comparison, Quantity),
+    Pressed(Option<Port>),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Comparison {
+    Less,
+    Greater,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Port {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
 }
 
 #[derive(Debug, Clone, Copy)]
+pub enum Color {
+    Black,
+    Magenta,
+    Purple,
+    Blue,
+    Azure,
+    Turquoise,
+    Green,
+    Yellow,
+    Orange,
+    Red,
+    White,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(f32),
+    Var(String),
+    Global(&'static str),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, BinOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinOp {
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Add | BinOp::Sub => 1,
+            BinOp::Mul | BinOp::Div => 2,
+        }
+    }
+}
+
+impl Expr {
+    fn fmt_python(&self, f: &mut fmt::Formatter<'_>, precedence: u8) -> fmt::Result {
+        match self {
+            Expr::Number(n) if *n < 0.0 && precedence > 0 => write!(f, "({n})"),
+            Expr::Number(n) => write!(f, "{n}"),
+            Expr::Var(name) => write!(f, "var_{name}"),
+            Expr::Global(name) => f.write_str(name),
+            Expr::Neg(expr) => write!(f, "{}", Neg(expr)),
+            Expr::Binary(lhs, op, rhs) => {
+                let p = op.precedence();
+                if p < precedence {
+                    f.write_str("(")?;
+                }
+                lhs.fmt_python(f, p)?;
+                f.write_str(match op {
+                    BinOp::Add => " + ",
+                    BinOp::Sub => " - ",
+                    BinOp::Mul => " * ",
+                    BinOp::Div => " / ",
+                })?;
+                rhs.fmt_python(f, p + 1)?;
+                if p < precedence {
+                    f.write_str(")")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_python(f, 0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dimension {
+    Length,
+    Angle,
+    Time,
+    Speed,
+    Percent,
+    Force,
+    Frequency,
+}
+
+impl Dimension {
+    fn name(self) -> &'static str {
+        match self {
+            Dimension::Length => "a length",
+            Dimension::Angle => "an angle",
+            Dimension::Time => "a time",
+            Dimension::Speed => "a speed",
+            Dimension::Percent => "a percentage",
+            Dimension::Force => "a force",
+            Dimension::Frequency => "a frequency",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Quantity {
+    pub value: Expr,
+    pub dimension: Dimension,
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.dimension {
+            Dimension::Speed => {
+                self.value.fmt_python(f, 2)?;
+                f.write_str(" * 100 / SPEED")
+            }
+            _ => self.value.fmt(f),
+        }
+    }
+}
+
+struct Neg<'a>(&'a Expr);
+
+impl fmt::Display for Neg<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("-")?;
+        self.0.fmt_python(f, 3)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Command {
-    MoveForward(f32),
-    MoveBackward(f32),
-    RotateRight(f32),
-    RotateLeft(f32),
-    FrontArmUp(f32),
-    FrontArmDown(f32),
-    BackArmUp(f32),
-    BackArmDown(f32),
+    MoveForward {
+        amount: Quantity,
+        speed: Option<Quantity>,
+        steering: Option<Expr>,
+    },
+    MoveBackward {
+        amount: Quantity,
+        speed: Option<Quantity>,
+        steering: Option<Expr>,
+    },
+    RotateRight {
+        amount: Quantity,
+        speed: Option<Quantity>,
+        gyro: bool,
+    },
+    RotateLeft {
+        amount: Quantity,
+        speed: Option<Quantity>,
+        gyro: bool,
+    },
+    CurveLeft {
+        amount: Quantity,
+        radius: Quantity,
+        speed: Option<Quantity>,
+    },
+    CurveRight {
+        amount: Quantity,
+        radius: Quantity,
+        speed: Option<Quantity>,
+    },
+    TurnToHeading {
+        heading: Quantity,
+        speed: Option<Quantity>,
+    },
+    FrontArmUp {
+        amount: Quantity,
+        speed: Option<Quantity>,
+    },
+    FrontArmDown {
+        amount: Quantity,
+        speed: Option<Quantity>,
+    },
+    BackArmUp {
+        amount: Quantity,
+        speed: Option<Quantity>,
+    },
+    BackArmDown {
+        amount: Quantity,
+        speed: Option<Quantity>,
+    },
+    MoveForwardUntil {
+        condition: Condition,
+        max: Option<Quantity>,
+        speed: Option<Quantity>,
+    },
+    MoveBackwardUntil {
+        condition: Condition,
+        max: Option<Quantity>,
+        speed: Option<Quantity>,
+    },
+    RotateRightUntil {
+        condition: Condition,
+        max: Option<Quantity>,
+        speed: Option<Quantity>,
+    },
+    RotateLeftUntil {
+        condition: Condition,
+        max: Option<Quantity>,
+        speed: Option<Quantity>,
+    },
+    SetSpeed(Quantity),
+    Wait(Quantity),
+    Show(String),
+    ShowImage(String),
+    ClearDisplay,
+    Beep {
+        frequency: Quantity,
+        duration: Option<Quantity>,
+    },
+    PlaySound(String),
     PyDebug,
     Nop,
 }
 
-pub fn transpile(code: Vec<Command>) -> String {
-    let mut script = String::new();
-    for i in code {
-        match i {
-            Command::MoveForward(amount) => {
-                script.push_str(format!("    await move({amount})").as_str())
-            }
-            Command::MoveBackward(amount) => {
-                script.push_str(format!("    await move(-{amount})").as_str())
+pub fn check_sensors(program: &Program, config: &RobotConfig) -> Vec<Diagnostic> {
+    let ports = config.with_overrides(&program.config).ports;
+    let mut diagnostics = Vec::new();
+    let bodies = program
+        .procedures
+        .iter()
+        .map(|procedure| &procedure.body)
+        .chain(program.missions.iter().map(|mission| &mission.body))
+        .chain([&program.script]);
+    for body in bodies {
+        check_block(body, &ports, &mut diagnostics);
+    }
+    diagnostics
+}
+
+fn check_block(code: &[Spanned<Statement>], ports: &Ports, diagnostics: &mut Vec<Diagnostic>) {
+    for statement in code {
+        let mut conditions = Vec::new();
+        match &statement.node {
+            Statement::If(condition, then, otherwise) => {
+                conditions.push(condition);
+                check_block(then, ports, diagnostics);
+                check_block(otherwise, ports, diagnostics);
             }
-            Command::RotateRight(amount) => {
-                script.push_str(format!("    await rot({amount})").as_str())
+            Statement::Repeat(_, body) => check_block(body, ports, diagnostics),
+            Statement::Command(command) => conditions.extend(command.condition()),
+            Statement::Together(commands) => {
+                conditions.extend(commands.iter().filter_map(Command::condition))
             }
-            Command::RotateLeft(amount) => {
-                script.push_str(format!("    await rot(-{amount})").as_str())
+            Statement::Call(..) | Statement::Let(..) => {}
+        }
+        for condition in conditions {
+            check_condition(condition, statement.span, ports, diagnostics);
+        }
+    }
+}
+
+fn check_condition(
+    condition: &Condition,
+    span: Span,
+    ports: &Ports,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let (sensor, port) = match condition {
+        Condition::Color(None, _) => ("color sensor", ports.color_sensor),
+        Condition::Distance(None, ..) => ("distance sensor", ports.distance_sensor),
+        Condition::Force(None, ..) | Condition::Pressed(None) => {
+            ("force sensor", ports.force_sensor)
+        }
+        Condition::Not(condition) => return check_condition(condition, span, ports, diagnostics),
+        Condition::And(lhs, rhs) | Condition::Or(lhs, rhs) => {
+            check_condition(lhs, span, ports, diagnostics);
+            return check_condition(rhs, span, ports, diagnostics);
+        }
+        Condition::Color(..)
+        | Condition::Distance(..)
+        | Condition::Force(..)
+        | Condition::Pressed(_) => return,
+    };
+    if port.is_none() {
+        diagnostics.push(
+            Diagnostic::error(Code::MissingPort, span, format!("the {sensor} has no port"))
+                .with_help(format!(
+                    "add \"{sensor} port <A-F>\" to a \"config\" block or to the robot config file"
+                )),
+        );
+    }
+}
+
+impl Command {
+    fn condition(&self) -> Option<&Condition> {
+        match self {
+            Command::MoveForwardUntil { condition, .. }
+            | Command::MoveBackwardUntil { condition, .. }
+            | Command::RotateRightUntil { condition, .. }
+            | Command::RotateLeftUntil { condition, .. } => Some(condition),
+            _ => None,
+        }
+    }
+}
+
+pub fn transpile(program: Program, config: &RobotConfig) -> String {
+    let mut imports = BTreeSet::new();
+    let mut procedures = String::new();
+    for procedure in &program.procedures {
+        let params = procedure
+            .params
+            .iter()
+            .map(|param| format!("var_{}", param.name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        procedures.push_str(format!("async def proc_{}({params}):\n", procedure.name).as_str());
+        transpile_block(&mut procedures, &mut imports, &procedure.body, 1);
+        procedures.push_str("    pass\n\n");
+    }
+    for (i, mission) in program.missions.iter().enumerate() {
+        procedures.push_str(format!("async def mission_{}():\n", i + 1).as_str());
+        transpile_block(&mut procedures, &mut imports, &mission.body, 1);
+        procedures.push_str("    pass\n\n");
+    }
+
+    let mut script = String::new();
+    transpile_block(&mut script, &mut imports, &program.script, 1);
+    if !program.missions.is_empty() {
+        let missions = program
+            .missions
+            .iter()
+            .enumerate()
+            .map(|(i, mission)| format!("({}, mission_{})", python_string(&mission.name), i + 1))
+            .collect::<Vec<_>>()
+            .join(", ");
+        script.push_str(format!("    await menu([{missions}])\n").as_str());
+    }
+    script.push_str("    pass");
+
+    let imports = imports
+        .into_iter()
+        .map(|module| format!("import {module}\n"))
+        .collect::<String>();
+
+    PYTHON_SHELL
+        .replace("<IMPORTS>", &imports[..])
+        .replace(
+            "<CONFIG>",
+            &transpile_config(&config.with_overrides(&program.config))[..],
+        )
+        .replace("<PROCEDURES>", &procedures[..])
+        .replace("<SCRIPT>", &script[..])
+}
+
+fn transpile_config(config: &RobotConfig) -> String {
+    let ports = &config.ports;
+    format!(
+        "SPEED = {}
+DRIVE_SPEED = {}
+ARM_SPEED = {}
+ARMF_RATIO = {}
+ARMB_RATIO = {}
+ARMF = port.{:?}
+ARMB = port.{:?}
+LEFT = port.{:?}
+RIGHT = port.{:?}
+COLOR = {}
+DISTANCE = {}
+FORCE = {}
+CIRC = {}
+TRACK = {}
+",
+        config.max_speed,
+        config.drive_speed,
+        config.arm_speed,
+        config.front_arm_ratio,
+        config.back_arm_ratio,
+        ports.front_arm,
+        ports.back_arm,
+        ports.left_motor,
+        ports.right_motor,
+        python_port(ports.color_sensor),
+        python_port(ports.distance_sensor),
+        python_port(ports.force_sensor),
+        config.wheel_circumference,
+        config.track_width,
+    )
+}
+
+fn python_port(port: Option<Port>) -> String {
+    match port {
+        Some(port) => format!("port.{port:?}"),
+        None => String::from("None"),
+    }
+}
+
+fn transpile_block(
+    script: &mut String,
+    imports: &mut BTreeSet<&'static str>,
+    code: &[Spanned<Statement>],
+    depth: usize,
+) {
+    let indent = "    ".repeat(depth);
+    for i in code {
+        match &i.node {
+            Statement::Command(Command::Nop) => {}
+            Statement::Command(command) => script
+                .push_str(format!("{indent}{}\n", transpile_command(command, imports)).as_str()),
+            Statement::Repeat(count, body) => {
+                let count = match count {
+                    Expr::Number(_) => count.to_string(),
+                    _ => format!("int({count})"),
+                };
+                script.push_str(format!("{indent}for _ in range({count}):\n").as_str());
+                if body.is_empty() {
+                    script.push_str(format!("{indent}    pass\n").as_str());
+                }
+                transpile_block(script, imports, body, depth + 1);
             }
-            Command::FrontArmUp(amount) => {
-                script.push_str(format!("    await armF({amount})").as_str())
+            Statement::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                script.push_str(format!("{indent}await proc_{name}({args})\n").as_str())
             }
-            Command::FrontArmDown(amount) => {
-                script.push_str(format!("    await armF(-{amount})").as_str())
+            Statement::Let(name, value) => {
+                script.push_str(format!("{indent}var_{name} = {value}\n").as_str())
             }
-            Command::BackArmUp(amount) => {
-                script.push_str(format!("    await armB({amount})").as_str())
+            Statement::Together(commands) => {
+                let actions = commands
+                    .iter()
+                    .filter_map(transpile_action)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                script.push_str(format!("{indent}await run({actions})\n").as_str())
             }
-            Command::BackArmDown(amount) => {
-                script.push_str(format!("    await armB(-{amount})").as_str())
+            Statement::If(condition, then, otherwise) => {
+                let condition = transpile_condition(condition, imports);
+                script.push_str(format!("{indent}if {condition}:\n").as_str());
+                if then.is_empty() {
+                    script.push_str(format!("{indent}    pass\n").as_str());
+                }
+                transpile_block(script, imports, then, depth + 1);
+                if !otherwise.is_empty() {
+                    script.push_str(format!("{indent}else:\n").as_str());
+                    transpile_block(script, imports, otherwise, depth + 1);
+                }
             }
-            Command::PyDebug => script.push_str("    await debug()"),
-            Command::Nop => {}
         }
-        script.push('\n');
     }
-    script.push_str("    pass");
+}
+
+fn transpile_condition(condition: &Condition, imports: &mut BTreeSet<&'static str>) -> String {
+    let port = |port: &Option<Port>, default: &str| match port {
+        Some(port) => format!("port.{port:?}"),
+        None => String::from(default),
+    };
+    let comparison = |comparison: &Comparison| match comparison {
+        Comparison::Less => "<",
+        Comparison::Greater => ">",
+    };
 
-    PYTHON_SHELL.replace("<SCRIPT>", &script[..])
+    match condition {
+        Condition::Color(p, color) => {
+            imports.insert("color");
+            imports.insert("color_sensor");
+            let color = format!("{color:?}").to_uppercase();
+            format!("color_sensor.color({}) == color.{color}", port(p, "COLOR"))
+        }
+        Condition::Distance(p, op, cm) => {
+            imports.insert("distance_sensor");
+            let op = comparison(op);
+            format!("distance_cm({}) {op} {cm}", port(p, "DISTANCE"))
+        }
+        Condition::Force(p, op, newtons) => {
+            imports.insert("force_sensor");
+            let op = comparison(op);
+            format!(
+                "force_sensor.force({}) / 10 {op} {newtons}",
+                port(p, "FORCE")
+            )
+        }
+        Condition::Pressed(p) => {
+            imports.insert("force_sensor");
+            format!("force_sensor.pressed({})", port(p, "FORCE"))
+        }
+        Condition::Not(condition) => format!("not ({})", transpile_condition(condition, imports)),
+        Condition::And(lhs, rhs) => format!(
+            "({}) and ({})",
+            transpile_condition(lhs, imports),
+            transpile_condition(rhs, imports)
+        ),
+        Condition::Or(lhs, rhs) => format!(
+            "({}) or ({})",
+            transpile_condition(lhs, imports),
+            transpile_condition(rhs, imports)
+        ),
+    }
+}
+
+fn transpile_command(command: &Command, imports: &mut BTreeSet<&'static str>) -> String {
+    match command {
+        Command::MoveForwardUntil {
+            condition,
+            max,
+            speed,
+        } => until("move_until", 1, condition, max, speed, imports),
+        Command::MoveBackwardUntil {
+            condition,
+            max,
+            speed,
+        } => until("move_until", -1, condition, max, speed, imports),
+        Command::RotateRightUntil {
+            condition,
+            max,
+            speed,
+        } => until("rot_until", 1, condition, max, speed, imports),
+        Command::RotateLeftUntil {
+            condition,
+            max,
+            speed,
+        } => until("rot_until", -1, condition, max, speed, imports),
+        Command::RotateRight {
+            amount,
+            speed,
+            gyro: true,
+        } => format!("await {}", motion("gyro_rot", amount, speed)),
+        Command::RotateLeft {
+            amount,
+            speed,
+            gyro: true,
+        } => format!("await {}", motion("gyro_rot", Neg(&amount.value), speed)),
+        Command::TurnToHeading { heading, speed } => {
+            format!("await {}", motion("turn_to", heading, speed))
+        }
+        Command::SetSpeed(percent) => format!("set_speed({percent})"),
+        Command::Wait(ms) => format!("await runloop.sleep_ms({})", millis(&ms.value)),
+        Command::Show(text) => format!("await light_matrix.write({})", python_string(text)),
+        Command::ShowImage(image) => format!("light_matrix.show_image(light_matrix.IMAGE_{image})"),
+        Command::ClearDisplay => String::from("light_matrix.clear()"),
+        Command::PlaySound(name) => format!("await play_sound({})", python_string(name)),
+        Command::PyDebug => String::from("await debug()"),
+        Command::Nop => String::new(),
+        action => format!("await run({})", transpile_action(action).unwrap()),
+    }
+}
+
+fn transpile_action(command: &Command) -> Option<String> {
+    let action = match command {
+        Command::MoveForward {
+            amount,
+            speed,
+            steering,
+        } => steer(amount, speed, steering),
+        Command::MoveBackward {
+            amount,
+            speed,
+            steering,
+        } => steer(Neg(&amount.value), speed, steering),
+        Command::CurveRight {
+            amount,
+            radius,
+            speed,
+        } => format!("curve({amount}, {radius}, 1, {})", optional(speed)),
+        Command::CurveLeft {
+            amount,
+            radius,
+            speed,
+        } => format!("curve({amount}, {radius}, -1, {})", optional(speed)),
+        Command::RotateRight {
+            amount,
+            speed,
+            gyro: false,
+        } => motion("rot", amount, speed),
+        Command::RotateLeft {
+            amount,
+            speed,
+            gyro: false,
+        } => motion("rot", Neg(&amount.value), speed),
+        Command::FrontArmUp { amount, speed } => motion("armF", amount, speed),
+        Command::FrontArmDown { amount, speed } => motion("armF", Neg(&amount.value), speed),
+        Command::BackArmUp { amount, speed } => motion("armB", amount, speed),
+        Command::BackArmDown { amount, speed } => motion("armB", Neg(&amount.value), speed),
+        Command::Wait(ms) => format!("wait({})", millis(&ms.value)),
+        Command::Beep {
+            frequency,
+            duration: Some(ms),
+        } => format!("beep({frequency}, {})", millis(&ms.value)),
+        Command::Beep {
+            frequency,
+            duration: None,
+        } => format!("beep({frequency})"),
+        Command::RotateRight { gyro: true, .. }
+        | Command::RotateLeft { gyro: true, .. }
+        | Command::TurnToHeading { .. }
+        | Command::MoveForwardUntil { .. }
+        | Command::MoveBackwardUntil { .. }
+        | Command::RotateRightUntil { .. }
+        | Command::RotateLeftUntil { .. }
+        | Command::SetSpeed(_)
+        | Command::Show(_)
+        | Command::ShowImage(_)
+        | Command::ClearDisplay
+        | Command::PlaySound(_)
+        | Command::PyDebug
+        | Command::Nop => return None,
+    };
+    Some(action)
+}
+
+fn motion(helper: &str, amount: impl fmt::Display, speed: &Option<Quantity>) -> String {
+    match speed {
+        Some(speed) => format!("{helper}({amount}, {speed})"),
+        None => format!("{helper}({amount})"),
+    }
+}
+
+fn until(
+    helper: &str,
+    direction: i8,
+    condition: &Condition,
+    max: &Option<Quantity>,
+    speed: &Option<Quantity>,
+    imports: &mut BTreeSet<&'static str>,
+) -> String {
+    let condition = transpile_condition(condition, imports);
+    format!(
+        "await {helper}(lambda: {condition}, {direction}, {}, {})",
+        optional(max),
+        optional(speed)
+    )
+}
+
+fn steer(amount: impl fmt::Display, speed: &Option<Quantity>, steering: &Option<Expr>) -> String {
+    match steering {
+        Some(steering) => format!("move({amount}, {}, {steering})", optional(speed)),
+        None => motion("move", amount, speed),
+    }
+}
+
+fn optional(value: &Option<impl fmt::Display>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => String::from("None"),
+    }
+}
+
+fn python_string(text: &str) -> String {
+    let mut string = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            '\n' => string.push_str("\\n"),
+            '\t' => string.push_str("\\t"),
+            c if c.is_control() => string.push_str(&format!("\\x{:02x}", c as u32)),
+            c => string.push(c),
+        }
+    }
+    string.push('"');
+    string
+}
+
+fn millis(ms: &Expr) -> String {
+    match ms {
+        Expr::Number(ms) => ms.round().to_string(),
+        _ => format!("int({ms})"),
+    }
+}
+
+impl Command {
+    fn is_action(&self) -> bool {
+        transpile_action(self).is_some()
+    }
 }
 
 const PYTHON_SHELL: &str = r#"from hub import light_matrix
+from hub import button
+from hub import motion_sensor
 from hub import port
 from hub import sound
 import motor_pair
 import motor
 import runloop
 import math
-
-SPEED = 1110
-ARMF = port.E
-ARMB = port.F
-LEFT = port.C
-RIGHT = port.D
+import time
+<IMPORTS>
+<CONFIG>velocity = int(SPEED * DRIVE_SPEED / 100)
+arm_velocity = int(SPEED * ARM_SPEED / 100)
 
 async def main():
     motor_pair.pair(motor_pair.PAIR_1, LEFT, RIGHT)
+    motion_sensor.reset_yaw(0)
     await script()
 
-async def armF(deg: float):
-    motor.run_for_degrees(ARMF, int(deg), SPEED)
+def set_speed(percent: float):
+    global velocity, arm_velocity
+    velocity = int(SPEED * percent / 100)
+    arm_velocity = velocity
+
+def vel(percent):
+    if percent is None:
+        return velocity
+    return int(SPEED * percent / 100)
+
+def arm_vel(percent):
+    if percent is None:
+        return arm_velocity
+    return vel(percent)
+
+async def run(*actions):
+    for action in actions:
+        for awaitable in action:
+            await awaitable
+
+def armF(deg: float, speed=None):
+    return [motor.run_for_degrees(ARMF, int(deg * ARMF_RATIO), arm_vel(speed))]
+
+def armB(deg: float, speed=None):
+    return [motor.run_for_degrees(ARMB, int(deg * ARMB_RATIO), arm_vel(speed))]
+
+def move(cm: float, speed=None, steering=0):
+    return [motor_pair.move_for_degrees(motor_pair.PAIR_1, int(cm / CIRC * 360), int(steering), velocity=vel(speed))]
+
+def curve(cm: float, radius: float, direction: int, speed=None):
+    outer = vel(speed)
+    inner = int(outer * (radius - TRACK / 2) / (radius + TRACK / 2))
+    degrees = int(cm * (radius + TRACK / 2) / radius / CIRC * 360)
+    if direction > 0:
+        return [motor_pair.move_tank_for_degrees(motor_pair.PAIR_1, degrees, outer, inner)]
+    return [motor_pair.move_tank_for_degrees(motor_pair.PAIR_1, degrees, inner, outer)]
+
+def rot(deg: float, speed=None):
+    return [
+        motor.run_for_degrees(LEFT, -int(deg * 2), vel(speed)),
+        motor.run_for_degrees(RIGHT, -int(deg * 2), vel(speed)),
+    ]
+
+async def move_until(condition, direction: int, max_cm=None, speed=None):
+    motor.reset_relative_position(LEFT, 0)
+    motor_pair.move(motor_pair.PAIR_1, 0, velocity=direction * vel(speed))
+    while not condition():
+        if max_cm is not None and abs(motor.relative_position(LEFT)) >= max_cm / CIRC * 360:
+            break
+        await runloop.sleep_ms(10)
+    motor_pair.stop(motor_pair.PAIR_1)
+
+async def rot_until(condition, direction: int, max_deg=None, speed=None):
+    motor.reset_relative_position(LEFT, 0)
+    motor_pair.move(motor_pair.PAIR_1, direction * 100, velocity=vel(speed))
+    while not condition():
+        if max_deg is not None and abs(motor.relative_position(LEFT)) >= max_deg * 2:
+            break
+        await runloop.sleep_ms(10)
+    motor_pair.stop(motor_pair.PAIR_1)
+
+def yaw():
+    return -motion_sensor.tilt_angles()[0] / 10
+
+def gyro_vel(error: float, speed=None):
+    return max(100, min(vel(speed), int(abs(error) * 10)))
+
+async def turn_to(heading: float, speed=None):
+    while True:
+        error = (heading - yaw() + 180) % 360 - 180
+        if abs(error) < 1:
+            break
+        motor_pair.move(motor_pair.PAIR_1, 100 if error > 0 else -100, velocity=gyro_vel(error, speed))
+        await runloop.sleep_ms(10)
+    motor_pair.stop(motor_pair.PAIR_1)
+
+async def gyro_rot(deg: float, speed=None):
+    turned = 0
+    last = yaw()
+    while abs(deg - turned) >= 1:
+        motor_pair.move(motor_pair.PAIR_1, 100 if deg > turned else -100, velocity=gyro_vel(deg - turned, speed))
+        await runloop.sleep_ms(10)
+        now = yaw()
+        turned += (now - last + 180) % 360 - 180
+        last = now
+    motor_pair.stop(motor_pair.PAIR_1)
+
+def wait(ms: int):
+    return [sleep_until(time.ticks_add(time.ticks_ms(), ms))]
+
+async def sleep_until(deadline: int):
+    await runloop.sleep_ms(max(0, time.ticks_diff(deadline, time.ticks_ms())))
 
-async def armB(deg: float):
-    motor.run_for_degrees(ARMB, int(deg), SPEED)
+def distance_cm(p):
+    mm = distance_sensor.distance(p)
+    return 1000 if mm < 0 else mm / 10
 
-CIRC = 17.5
+def beep(freq: float, ms=500):
+    return [sound.beep(int(freq), ms)]
 
-async def move(cm: float):
-    await motor_pair.move_for_degrees(motor_pair.PAIR_1, int(cm / CIRC * 360), 0, velocity=SPEED)
+async def play_sound(name: str):
+    from app import sound as app_sound
+    await app_sound.play(name)
 
-async def rot(deg: float):
-    motor.run_for_degrees(LEFT, -int(deg * 2), SPEED)
-    await motor.run_for_degrees(RIGHT, -int(deg * 2), SPEED)
+# the center button is the system stop button, so the menu only uses LEFT and
+# RIGHT: a single press picks the previous or next mission, pressing both runs it
+async def next_button():
+    while not (button.pressed(button.LEFT) or button.pressed(button.RIGHT)):
+        await runloop.sleep_ms(10)
+    left = right = False
+    while button.pressed(button.LEFT) or button.pressed(button.RIGHT):
+        left = left or button.pressed(button.LEFT)
+        right = right or button.pressed(button.RIGHT)
+        await runloop.sleep_ms(10)
+    if left and right:
+        return None
+    return button.LEFT if left else button.RIGHT
+
+async def menu(missions):
+    print("LEFT/RIGHT: choose a mission, LEFT+RIGHT: run it")
+    selected = 0
+    while True:
+        await light_matrix.write(str(selected + 1))
+        pressed = await next_button()
+        if pressed == button.LEFT:
+            selected = (selected - 1) % len(missions)
+        elif pressed == button.RIGHT:
+            selected = (selected + 1) % len(missions)
+        else:
+            name, mission = missions[selected]
+            print("running mission", selected + 1, name)
+            await mission()
+            light_matrix.clear()
+            selected = (selected + 1) % len(missions)
 
 async def debug():
     await light_matrix.write("This is a debug message.")
 
-async def script():
+<PROCEDURES>async def script():
 <SCRIPT>
 runloop.run(main())"#;
+
+#[cfg(test)]
+mod tests {
+    use crate::{Code, Ports, Program, RobotConfig, Sources, check_sensors, transpile};
+
+    fn parse(code: &str) -> Program {
+        let mut sources = Sources::new();
+        sources.add("test.rbs", code.to_string());
+        let (program, diagnostics) = crate::parse(&mut sources);
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+        program
+    }
+
+    #[test]
+    fn default_ports_are_distinct() {
+        assert_eq!(Ports::default().check(), Ok(()));
+    }
+
+    #[test]
+    fn sensor_without_port() {
+        let program = parse("move forward until force > 5\nif color is red then\nend\n");
+        let diagnostics = check_sensors(&program, &RobotConfig::default());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Code::MissingPort);
+        assert_eq!(diagnostics[0].message, "the force sensor has no port");
+
+        let mut config = RobotConfig::default();
+        config.ports.color_sensor = None;
+        let program = parse("config\n  force sensor port A\nend\nmove forward until force > 5\n");
+        assert!(check_sensors(&program, &config).is_empty());
+    }
+
+    fn python(code: &str) -> String {
+        transpile(parse(code), &RobotConfig::default())
+    }
+
+    fn script(code: &str) -> String {
+        let python = python(code);
+        let start = python.find("async def script():\n").unwrap();
+        let end = python.find("runloop.run(main())").unwrap();
+        python[start..end].to_string()
+    }
+
+    #[test]
+    fn sequential_commands_await_one_run_each() {
+        assert_eq!(
+            script("move forward 10\nfront arm up 90\nwait 1 s\n"),
+            "async def script():
+    await run(move(10))
+    await run(armF(90))
+    await runloop.sleep_ms(1000)
+    pass
+"
+        );
+    }
+
+    #[test]
+    fn together_awaits_one_run() {
+        assert_eq!(
+            script("together\n  move forward 5\n  back arm down 45\nend\nrotate left 90\n"),
+            "async def script():
+    await run(move(5), armB(-45))
+    await run(rot(-90))
+    pass
+"
+        );
+    }
+
+    #[test]
+    fn nested_blocks_are_indented() {
+        assert_eq!(
+            script(
+                "repeat 2 times\n  if color is red then\n    rotate left 90\n  else\n    wait 1 s\n  end\nend\nrepeat 3 times\nend\n"
+            ),
+            "async def script():
+    for _ in range(2):
+        if color_sensor.color(COLOR) == color.RED:
+            await run(rot(-90))
+        else:
+            await runloop.sleep_ms(1000)
+    for _ in range(3):
+        pass
+    pass
+"
+        );
+    }
+
+    #[test]
+    fn procedures_and_config() {
+        let python = python("define f(a)\n  move forward a\nend\nf(2)\n");
+        assert!(python.contains(
+            "async def proc_f(var_a):
+    await run(move(var_a))
+    pass
+"
+        ));
+        assert!(python.contains("    await proc_f(2)\n"));
+        assert!(python.contains("COLOR = port.A\nDISTANCE = port.B\nFORCE = None\n"));
+    }
+}
@@ -1,238 +1,1765 @@
-use std::f32::consts::PI;
+use std::{collections::HashMap, f32::consts::PI, iter::Peekable, mem};
 
 use crate::keywords::IntToken;
-use peek_again::{Peekable, PeekableIterator};
 
 use crate::{
-    Command,
-    lexer::{Lexer, Token},
+    BinOp, Code, Color, Command, Comparison, Condition, Config, Diagnostic, Dimension, Expr,
+    Mission, Parameter, Port, Procedure, Program, Quantity, Span, Spanned, Statement,
+    lexer::Token,
+    source::{Sources, Tokens},
 };
 
 pub struct Parser<'src> {
-    lexer: Peekable<Lexer<'src>>,
-    errors: Vec<String>,
+    lexer: Peekable<Tokens<'src>>,
+    span: Span,
+    start: Span,
+    config: Config,
+    procedures: Vec<Procedure>,
+    procedure_indices: HashMap<String, usize>,
+    missions: Vec<Mission>,
+    calls: Vec<Call>,
+    defining: Vec<Option<usize>>,
+    variables: HashMap<String, Dim>,
+    params: Vec<Dim>,
+    depth: usize,
+    lints: bool,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl<'src> Parser<'src> {
-    pub fn new(src: &'src str) -> Self {
+    pub fn new(sources: &'src Sources, lints: bool) -> Self {
         Parser {
-            lexer: Lexer::new(src).peek_again(),
-            errors: Vec::new(),
+            lexer: Tokens::new(sources).peekable(),
+            span: Span::default(),
+            start: Span::default(),
+            config: Config::default(),
+            procedures: Vec::new(),
+            procedure_indices: HashMap::new(),
+            missions: Vec::new(),
+            calls: Vec::new(),
+            defining: Vec::new(),
+            variables: HashMap::new(),
+            params: Vec::new(),
+            depth: 0,
+            lints,
+            diagnostics: Vec::new(),
         }
     }
 
-    fn add_error(&mut self, error: impl ToString) {
-        self.errors.push(error.to_string());
+    fn add_error(&mut self, code: Code, error: impl ToString) {
+        self.add_error_at(code, self.span, error);
+    }
+
+    fn add_error_at(&mut self, code: Code, span: Span, error: impl ToString) {
+        self.report(Diagnostic::error(code, span, error));
+    }
+
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if self.lints || diagnostic.is_error() {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn missing<T>(&mut self, error: NoValue, message: impl ToString) -> Option<T> {
+        if let NoValue::Missing = error {
+            self.add_error(Code::ExpectedToken, message);
+        }
+        None
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        self.lexer.peek().map(|token| &token.node)
     }
 
     fn is_eof(&mut self) -> bool {
-        self.lexer.peek().get().is_none()
+        self.peek().is_none()
     }
 
-    fn next_token(&mut self) -> Option<Token> {
-        self.lexer.next()
+    fn at_end(&mut self) -> bool {
+        self.depth > 0
+            && matches!(
+                self.peek(),
+                Some(Token::Int(IntToken::End | IntToken::Else))
+            )
     }
 
-    pub fn command(&mut self) -> Option<Command> {
-        if self.is_eof() {
-            return None;
+    fn eat(&mut self, token: IntToken) -> bool {
+        if matches!(self.peek(), Some(Token::Int(t)) if *t == token) {
+            self.next_token();
+            true
+        } else {
+            false
         }
+    }
 
-        let token = self.next_token().unwrap();
+    fn eat_op(&mut self, op: char) -> bool {
+        if matches!(self.peek(), Some(Token::Op(c)) if *c == op) {
+            self.next_token();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_line(&mut self) {
+        while !matches!(self.peek(), Some(Token::Lf | Token::Errors(_)) | None) {
+            self.next_token();
+        }
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        let token = self.lexer.next()?;
+        self.span = token.span;
+        Some(token.node)
+    }
+
+    pub fn command(&mut self) -> Option<Spanned<Statement>> {
+        let outer = self.start;
+        let statement = loop {
+            if self.is_eof() || self.at_end() {
+                break None;
+            }
+            if let Some(statement) = self.statement() {
+                break Some(statement);
+            }
+        };
+        let span = self.start.to(self.span);
+        self.start = outer;
+        Some(Spanned {
+            node: statement?,
+            span,
+        })
+    }
+
+    fn statement(&mut self) -> Option<Statement> {
+        let token = self.next_token()?;
+        self.start = self.span;
 
         match token {
-            Token::Lf => self.command(),
+            Token::Lf => None,
             Token::Int(tok) => match tok {
                 IntToken::Deg => {
-                    self.add_error("unexpected \"deg\", ignoring");
-                    self.command()
+                    self.add_error(Code::UnexpectedToken, "unexpected \"deg\", ignoring");
+                    None
                 }
                 IntToken::In => {
-                    self.add_error("unexpected \"in\", ignoring");
-                    self.command()
+                    self.add_error(Code::UnexpectedToken, "unexpected \"in\", ignoring");
+                    None
                 }
                 IntToken::Rad => {
-                    self.add_error("unexpected \"rad\", ignoring");
-                    self.command()
+                    self.add_error(Code::UnexpectedToken, "unexpected \"rad\", ignoring");
+                    None
                 }
                 IntToken::Cm => {
-                    self.add_error("unexpected \"cm\", ignoring");
-                    self.command()
+                    self.add_error(Code::UnexpectedToken, "unexpected \"cm\", ignoring");
+                    None
                 }
-                IntToken::ArmBackDown => {
-                    if let Some(n) = self.deg() {
-                        Some(Command::BackArmDown(n))
-                    } else {
-                        self.add_error("expected number after \"back arm down\". ignoring");
-                        self.command()
-                    }
+                IntToken::S => {
+                    self.add_error(Code::UnexpectedToken, "unexpected \"s\", ignoring");
+                    None
+                }
+                IntToken::Ms => {
+                    self.add_error(Code::UnexpectedToken, "unexpected \"ms\", ignoring");
+                    None
                 }
+                IntToken::Min => {
+                    self.add_error(Code::UnexpectedToken, "unexpected \"min\", ignoring");
+                    None
+                }
+                IntToken::ArmBackDown => match self.deg() {
+                    Ok(amount) => {
+                        let speed = self.speed();
+                        Some(self.normalize(Command::BackArmDown { amount, speed }))
+                    }
+                    Err(error) => {
+                        self.missing(error, "expected number after \"back arm down\". ignoring")
+                    }
+                },
                 IntToken::MoveBackward => {
-                    if let Some(n) = self.cm() {
-                        Some(Command::MoveBackward(n))
+                    if self.eat(IntToken::Until) {
+                        self.until(tok)
                     } else {
-                        self.add_error("expected number after \"move backwards\". ignoring");
-                        self.command()
+                        match self.cm() {
+                            Ok(amount) => {
+                                let mut steering = self.steering();
+                                let speed = self.speed();
+                                if steering.is_none() {
+                                    steering = self.steering();
+                                }
+                                Some(self.normalize(Command::MoveBackward {
+                                    amount,
+                                    speed,
+                                    steering,
+                                }))
+                            }
+                            Err(error) => self.missing(
+                                error,
+                                "expected number after \"move backwards\". ignoring",
+                            ),
+                        }
                     }
                 }
-                IntToken::ArmBackUp => {
-                    if let Some(n) = self.deg() {
-                        Some(Command::BackArmUp(n))
-                    } else {
-                        self.add_error("expected number after \"back arm up\". ignoring");
-                        self.command()
+                IntToken::ArmBackUp => match self.deg() {
+                    Ok(amount) => {
+                        let speed = self.speed();
+                        Some(self.normalize(Command::BackArmUp { amount, speed }))
                     }
-                }
-                IntToken::ArmFrontDown => {
-                    if let Some(n) = self.deg() {
-                        Some(Command::FrontArmDown(n))
-                    } else {
-                        self.add_error("expected number after \"front arm down\". ignoring");
-                        self.command()
+                    Err(error) => {
+                        self.missing(error, "expected number after \"back arm up\". ignoring")
+                    }
+                },
+                IntToken::ArmFrontDown => match self.deg() {
+                    Ok(amount) => {
+                        let speed = self.speed();
+                        Some(self.normalize(Command::FrontArmDown { amount, speed }))
+                    }
+                    Err(error) => {
+                        self.missing(error, "expected number after \"front arm down\". ignoring")
+                    }
+                },
+                IntToken::ArmFrontUp => match self.deg() {
+                    Ok(amount) => {
+                        let speed = self.speed();
+                        Some(self.normalize(Command::FrontArmUp { amount, speed }))
+                    }
+                    Err(error) => {
+                        self.missing(error, "expected number after \"front arm up\". ignoring")
+                    }
+                },
+                IntToken::Debug => Some(Statement::Command(Command::PyDebug)),
+                IntToken::Show => match self.peek() {
+                    Some(Token::Str(text)) => {
+                        let text = text.clone();
+                        self.next_token();
+                        Some(Statement::Command(Command::Show(text)))
+                    }
+                    _ => {
+                        self.add_error(
+                            Code::ExpectedToken,
+                            "expected text in quotes after \"show\". ignoring",
+                        );
+                        self.skip_line();
+                        None
+                    }
+                },
+                IntToken::ShowImage => {
+                    let image = match self.peek() {
+                        Some(Token::Ident(name)) => Some(name.to_ascii_uppercase()),
+                        _ => None,
+                    };
+                    match image {
+                        Some(image) if IMAGES.contains(&image.as_str()) => {
+                            self.next_token();
+                            Some(Statement::Command(Command::ShowImage(image)))
+                        }
+                        Some(image) => {
+                            self.add_error(
+                                Code::InvalidValue,
+                                format!("unknown image \"{image}\". ignoring"),
+                            );
+                            self.skip_line();
+                            None
+                        }
+                        None => {
+                            self.add_error(
+                                Code::ExpectedToken,
+                                "expected image name after \"show image\". ignoring",
+                            );
+                            self.skip_line();
+                            None
+                        }
                     }
                 }
-                IntToken::ArmFrontUp => {
-                    if let Some(n) = self.deg() {
-                        Some(Command::FrontArmUp(n))
-                    } else {
-                        self.add_error("expected number after \"front arm up\". ignoring");
-                        self.command()
+                IntToken::ClearDisplay => Some(Statement::Command(Command::ClearDisplay)),
+                IntToken::SetSpeed => match self.percent() {
+                    Ok(n) => Some(Statement::Command(Command::SetSpeed(n))),
+                    Err(error) => {
+                        self.missing(error, "expected number after \"set speed\". ignoring")
                     }
+                },
+                IntToken::At => {
+                    self.add_error(Code::UnexpectedToken, "unexpected \"at\", ignoring");
+                    None
+                }
+                IntToken::Percent => {
+                    self.add_error(Code::UnexpectedToken, "unexpected \"%\", ignoring");
+                    None
                 }
-                IntToken::Debug => Some(Command::PyDebug),
+                IntToken::Wait => match self.ms() {
+                    Ok(n) => {
+                        if let Expr::Number(n) = n.value
+                            && n < 0.0
+                        {
+                            self.add_error(
+                                Code::InvalidValue,
+                                format!("cannot wait for a negative time, got {n}"),
+                            );
+                        }
+                        Some(Statement::Command(Command::Wait(n)))
+                    }
+                    Err(error) => self.missing(error, "expected number after \"wait\". ignoring"),
+                },
+                IntToken::Beep => self.beep(),
+                IntToken::PlaySound => match self.peek() {
+                    Some(Token::Str(name)) => {
+                        let name = name.clone();
+                        self.next_token();
+                        Some(Statement::Command(Command::PlaySound(name)))
+                    }
+                    _ => {
+                        self.add_error(
+                            Code::ExpectedToken,
+                            "expected sound name in quotes after \"play sound\". ignoring",
+                        );
+                        self.skip_line();
+                        None
+                    }
+                },
                 IntToken::RotateLeft => {
-                    if let Some(n) = self.deg() {
-                        Some(Command::RotateLeft(n))
+                    if self.eat(IntToken::Until) {
+                        self.until(tok)
                     } else {
-                        self.add_error("expected number after \"rotate left\". ignoring");
-                        self.command()
+                        match self.deg() {
+                            Ok(amount) => {
+                                let mut gyro = self.eat(IntToken::UsingGyro);
+                                let speed = self.speed();
+                                gyro |= self.eat(IntToken::UsingGyro);
+                                Some(self.normalize(Command::RotateLeft {
+                                    amount,
+                                    speed,
+                                    gyro,
+                                }))
+                            }
+                            Err(error) => self
+                                .missing(error, "expected number after \"rotate left\". ignoring"),
+                        }
                     }
                 }
                 IntToken::RotateRight => {
-                    if let Some(n) = self.deg() {
-                        Some(Command::RotateRight(n))
+                    if self.eat(IntToken::Until) {
+                        self.until(tok)
                     } else {
-                        self.add_error("expected number after \"rotate right\". ignoring");
-                        self.command()
+                        match self.deg() {
+                            Ok(amount) => {
+                                let mut gyro = self.eat(IntToken::UsingGyro);
+                                let speed = self.speed();
+                                gyro |= self.eat(IntToken::UsingGyro);
+                                Some(self.normalize(Command::RotateRight {
+                                    amount,
+                                    speed,
+                                    gyro,
+                                }))
+                            }
+                            Err(error) => self
+                                .missing(error, "expected number after \"rotate right\". ignoring"),
+                        }
                     }
                 }
                 IntToken::MoveForward => {
-                    if let Some(n) = self.cm() {
-                        Some(Command::MoveForward(n))
+                    if self.eat(IntToken::Until) {
+                        self.until(tok)
                     } else {
-                        self.add_error("expected number after \"move forward\". ignoring");
-                        self.command()
+                        match self.cm() {
+                            Ok(amount) => {
+                                let mut steering = self.steering();
+                                let speed = self.speed();
+                                if steering.is_none() {
+                                    steering = self.steering();
+                                }
+                                Some(self.normalize(Command::MoveForward {
+                                    amount,
+                                    speed,
+                                    steering,
+                                }))
+                            }
+                            Err(error) => self
+                                .missing(error, "expected number after \"move forward\". ignoring"),
+                        }
                     }
                 }
-                IntToken::Then => unreachable!(),
+                IntToken::CurveLeft | IntToken::CurveRight => match self.cm() {
+                    Ok(amount) => self.curve(tok, amount),
+                    Err(error) => self.missing(
+                        error,
+                        format!("expected number after \"{}\". ignoring", tok.name()),
+                    ),
+                },
+                IntToken::TurnToHeading => match self.deg() {
+                    Ok(heading) => {
+                        let speed = self.speed();
+                        Some(Statement::Command(Command::TurnToHeading {
+                            heading,
+                            speed,
+                        }))
+                    }
+                    Err(error) => {
+                        self.missing(error, "expected number after \"turn to heading\". ignoring")
+                    }
+                },
+                IntToken::Repeat => self.repeat(),
+                IntToken::Define => self.define(),
+                IntToken::Config => self.config(),
+                IntToken::Mission => self.mission(),
+                IntToken::Let => self.assignment(),
+                IntToken::Together => self.together(),
+                IntToken::Times => {
+                    self.add_error(Code::UnexpectedToken, "unexpected \"times\", ignoring");
+                    None
+                }
+                IntToken::End => {
+                    self.add_error(Code::UnexpectedToken, "unexpected \"end\", ignoring");
+                    None
+                }
+                IntToken::If => self.conditional(),
+                tok @ (IntToken::Else
+                | IntToken::Is
+                | IntToken::On
+                | IntToken::Not
+                | IntToken::And
+                | IntToken::Or
+                | IntToken::Under
+                | IntToken::Over
+                | IntToken::Color
+                | IntToken::Distance
+                | IntToken::Force
+                | IntToken::Pressed
+                | IntToken::Until
+                | IntToken::Max
+                | IntToken::UsingGyro
+                | IntToken::Radius
+                | IntToken::Steering
+                | IntToken::Newton
+                | IntToken::Hz
+                | IntToken::Khz
+                | IntToken::For
+                | IntToken::Mm
+                | IntToken::M
+                | IntToken::Ft
+                | IntToken::Rotations
+                | IntToken::WheelRotations
+                | IntToken::Dps
+                | IntToken::Rpm
+                | IntToken::CmPerS
+                | IntToken::Black
+                | IntToken::Magenta
+                | IntToken::Purple
+                | IntToken::Blue
+                | IntToken::Azure
+                | IntToken::Turquoise
+                | IntToken::Green
+                | IntToken::Yellow
+                | IntToken::Orange
+                | IntToken::Red
+                | IntToken::White) => {
+                    self.add_error(
+                        Code::UnexpectedToken,
+                        format!("unexpected \"{}\", ignoring", tok.name()),
+                    );
+                    None
+                }
+                IntToken::Then | IntToken::Include => unreachable!(),
             },
+            Token::Ident(name) => {
+                let args = if matches!(self.peek(), Some(Token::Op('('))) {
+                    self.next_token();
+                    self.arguments(&name)
+                } else {
+                    Some(Vec::new())
+                };
+                let Some(args) = args.and_then(|args| self.check_call(&name, args)) else {
+                    self.skip_line();
+                    return None;
+                };
+                Some(Statement::Call(name, args))
+            }
+            Token::Str(text) => {
+                self.add_error(
+                    Code::UnexpectedToken,
+                    format!("unexpected string \"{text}\", ignoring"),
+                );
+                None
+            }
+            Token::Op(c) => {
+                self.add_error(
+                    Code::UnexpectedToken,
+                    format!("unexpected \"{c}\", ignoring"),
+                );
+                None
+            }
             Token::Number(i) => {
-                self.add_error(format!("unexpected number {i}, ignoring"));
-                self.command()
+                self.add_error(
+                    Code::UnexpectedToken,
+                    format!("unexpected number {i}, ignoring"),
+                );
+                None
             }
             Token::Errors(mut i) => {
-                self.errors.append(&mut i);
+                self.diagnostics.append(&mut i);
                 None
             }
         }
     }
 
-    fn cm(&mut self) -> Option<f32> {
-        if let Some((n, s)) = self.number() {
-            let suffix = s.unwrap_or(NumberSuffix::Cm);
-            let number = match suffix {
-                NumberSuffix::Deg => {
-                    self.add_error("cannot convert degrees to centimeters");
-                    n
+    fn normalize(&mut self, mut command: Command) -> Statement {
+        let amount = match &mut command {
+            Command::MoveForward { amount, .. }
+            | Command::MoveBackward { amount, .. }
+            | Command::RotateRight { amount, .. }
+            | Command::RotateLeft { amount, .. }
+            | Command::FrontArmUp { amount, .. }
+            | Command::FrontArmDown { amount, .. }
+            | Command::BackArmUp { amount, .. }
+            | Command::BackArmDown { amount, .. } => amount,
+            _ => return Statement::Command(command),
+        };
+        match &mut amount.value {
+            Expr::Number(n) if *n < 0.0 => *n = -*n,
+            Expr::Neg(expr) => amount.value = mem::replace(expr, Expr::Number(0.0)),
+            _ => return Statement::Command(command),
+        }
+
+        let (from, to, command) = match command {
+            Command::MoveForward {
+                amount,
+                speed,
+                steering,
+            } => (
+                "move forward",
+                "move backward",
+                Command::MoveBackward {
+                    amount,
+                    speed,
+                    steering,
+                },
+            ),
+            Command::MoveBackward {
+                amount,
+                speed,
+                steering,
+            } => (
+                "move backward",
+                "move forward",
+                Command::MoveForward {
+                    amount,
+                    speed,
+                    steering,
+                },
+            ),
+            Command::RotateRight {
+                amount,
+                speed,
+                gyro,
+            } => (
+                "rotate right",
+                "rotate left",
+                Command::RotateLeft {
+                    amount,
+                    speed,
+                    gyro,
+                },
+            ),
+            Command::RotateLeft {
+                amount,
+                speed,
+                gyro,
+            } => (
+                "rotate left",
+                "rotate right",
+                Command::RotateRight {
+                    amount,
+                    speed,
+                    gyro,
+                },
+            ),
+            Command::FrontArmUp { amount, speed } => (
+                "front arm up",
+                "front arm down",
+                Command::FrontArmDown { amount, speed },
+            ),
+            Command::FrontArmDown { amount, speed } => (
+                "front arm down",
+                "front arm up",
+                Command::FrontArmUp { amount, speed },
+            ),
+            Command::BackArmUp { amount, speed } => (
+                "back arm up",
+                "back arm down",
+                Command::BackArmDown { amount, speed },
+            ),
+            Command::BackArmDown { amount, speed } => (
+                "back arm down",
+                "back arm up",
+                Command::BackArmUp { amount, speed },
+            ),
+            _ => unreachable!(),
+        };
+        self.report(
+            Diagnostic::warning(
+                Code::NegativeAmount,
+                self.start.to(self.span),
+                format!("negative amount turns \"{from}\" into \"{to}\""),
+            )
+            .with_help(format!("write \"{to}\" if that is intended")),
+        );
+        Statement::Command(command)
+    }
+
+    fn define(&mut self) -> Option<Statement> {
+        let name = match self.peek() {
+            Some(Token::Ident(name)) => name.clone(),
+            _ => {
+                self.add_error(
+                    Code::ExpectedToken,
+                    "expected procedure name after \"define\". ignoring",
+                );
+                return None;
+            }
+        };
+        self.next_token();
+
+        let span = self.span;
+        if self.depth > 0 {
+            self.add_error(
+                Code::Misplaced,
+                format!("procedure \"{name}\" must be defined outside of any block"),
+            );
+        }
+        let names = if self.eat_op('(') {
+            self.parameters(&name)
+        } else {
+            Vec::new()
+        };
+
+        let index = if let Some(&first) = self.procedure_indices.get(&name) {
+            let first = self.procedures[first].span;
+            self.report(
+                Diagnostic::error(
+                    Code::DuplicateDefinition,
+                    span,
+                    format!("procedure \"{name}\" is already defined"),
+                )
+                .with_label(first, "first defined here"),
+            );
+            None
+        } else {
+            let index = self.procedures.len();
+            self.procedure_indices.insert(name.clone(), index);
+            self.procedures.push(Procedure {
+                name,
+                params: names
+                    .iter()
+                    .map(|name| Parameter {
+                        name: name.clone(),
+                        dimension: None,
+                    })
+                    .collect(),
+                span,
+                body: Vec::new(),
+            });
+            Some(index)
+        };
+
+        let outer = mem::take(&mut self.variables);
+        let outer_params =
+            mem::replace(&mut self.params, (0..names.len()).map(Dim::Param).collect());
+        for (i, param) in names.iter().enumerate() {
+            self.variables.insert(param.clone(), Dim::Param(i));
+        }
+        self.defining.push(index);
+        let body = self.block("define");
+        self.defining.pop();
+        let dims = mem::replace(&mut self.params, outer_params);
+        self.variables = outer;
+
+        if let Some(index) = index {
+            let procedure = &mut self.procedures[index];
+            for (param, dim) in procedure.params.iter_mut().zip(dims) {
+                if let Dim::Known(dimension) = dim {
+                    param.dimension = dimension;
                 }
-                NumberSuffix::Rad => {
-                    self.add_error("cannot convert radians to centimeters");
-                    n
+            }
+            procedure.body = body;
+        }
+        None
+    }
+
+    fn parameters(&mut self, procedure: &str) -> Vec<String> {
+        let mut names = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Op(')')) => {
+                    self.next_token();
+                    break;
+                }
+                Some(Token::Op(',')) if !names.is_empty() => {
+                    self.next_token();
+                }
+                Some(Token::Ident(param)) => {
+                    let param = param.clone();
+                    self.next_token();
+                    if names.contains(&param) {
+                        self.add_error(
+                            Code::DuplicateDefinition,
+                            format!("parameter \"{param}\" of \"{procedure}\" is declared twice"),
+                        );
+                    } else {
+                        names.push(param);
+                    }
+                }
+                _ => {
+                    self.add_error(
+                        Code::ExpectedToken,
+                        format!("expected parameter names and \")\" after \"define {procedure}(\""),
+                    );
+                    self.skip_line();
+                    break;
+                }
+            }
+        }
+        names
+    }
+
+    fn arguments(&mut self, procedure: &str) -> Option<Vec<(Expr, Dim)>> {
+        let mut args = Vec::new();
+        loop {
+            if self.eat_op(')') {
+                return Some(args);
+            }
+            let arg = match self.number() {
+                Ok(arg) => arg,
+                Err(error) => {
+                    return self.missing(
+                        error,
+                        format!("expected arguments and \")\" after \"{procedure}(\". ignoring"),
+                    );
                 }
-                NumberSuffix::Cm => n,
-                NumberSuffix::In => n * 2.54,
             };
-            Some(number)
+            args.push(arg);
+            self.eat_op(',');
+        }
+    }
+
+    fn check_call(&mut self, name: &str, args: Vec<(Expr, Dim)>) -> Option<Vec<Expr>> {
+        let index = self.procedure_indices.get(name).copied();
+        if let Some(i) = index
+            && let Err(error) = check_arity(self.start, &self.procedures[i], args.len())
+        {
+            self.report(error);
+            return None;
+        }
+
+        let Some(i) = index.filter(|i| !self.defining.contains(&Some(*i))) else {
+            let (values, args) = args
+                .into_iter()
+                .map(|(value, dim)| (value, self.deferred(dim)))
+                .unzip();
+            self.calls.push(Call {
+                name: name.to_string(),
+                span: self.start,
+                args,
+            });
+            return Some(values);
+        };
+        let params = self.procedures[i]
+            .params
+            .iter()
+            .map(|p| p.dimension)
+            .collect::<Vec<_>>();
+
+        let mut values = Vec::new();
+        let mut valid = true;
+        for (n, ((value, dim), param)) in args.into_iter().zip(params).enumerate() {
+            let arg = self.known(dim, param);
+            match check_argument(name, n, arg, param) {
+                Ok(Some(suffix)) => values.push(convert(value, suffix)),
+                Ok(None) => values.push(value),
+                Err(message) => {
+                    self.report(call_error(self.start, &self.procedures[i], message));
+                    valid = false;
+                }
+            }
+        }
+        valid.then_some(values)
+    }
+
+    fn deferred(&self, dim: Dim) -> Arg {
+        match dim {
+            Dim::Known(dimension) => Arg::Known(dimension),
+            Dim::Param(i) => match (self.params[i], self.defining.last()) {
+                (Dim::Known(dimension), _) => Arg::Known(dimension),
+                (Dim::Param(_), Some(&Some(procedure))) => Arg::Param(procedure, i),
+                _ => Arg::Any,
+            },
+        }
+    }
+
+    fn mission(&mut self) -> Option<Statement> {
+        let name = match self.peek() {
+            Some(Token::Str(name)) => name.clone(),
+            _ => {
+                self.add_error(
+                    Code::ExpectedToken,
+                    "expected mission name in quotes after \"mission\". ignoring",
+                );
+                self.skip_line();
+                return None;
+            }
+        };
+        self.next_token();
+
+        if self.depth > 0 {
+            self.add_error(
+                Code::Misplaced,
+                format!("mission \"{name}\" must be defined outside of any block"),
+            );
+        }
+        let outer = mem::take(&mut self.variables);
+        let body = self.block("mission");
+        self.variables = outer;
+        if self.missions.iter().any(|m| m.name == name) {
+            self.add_error(
+                Code::DuplicateDefinition,
+                format!("mission \"{name}\" is already defined"),
+            );
         } else {
-            None
+            self.missions.push(Mission { name, body });
+        }
+        None
+    }
+
+    fn config(&mut self) -> Option<Statement> {
+        if self.depth > 0 {
+            self.add_error(Code::Misplaced, "\"config\" must be outside of any block");
+        }
+        self.depth += 1;
+        loop {
+            match self.peek() {
+                Some(Token::Lf) => {
+                    self.next_token();
+                }
+                Some(Token::Int(IntToken::End)) => {
+                    self.next_token();
+                    break;
+                }
+                Some(Token::Errors(_)) | None => {
+                    self.add_error(Code::ExpectedToken, "expected \"end\" to close \"config\"");
+                    break;
+                }
+                Some(_) => self.config_entry(),
+            }
         }
+        self.depth -= 1;
+        None
     }
 
-    fn deg(&mut self) -> Option<f32> {
-        if let Some((n, s)) = self.number() {
-            let suffix = s.unwrap_or(NumberSuffix::Deg);
-            let number = match suffix {
-                NumberSuffix::Cm => {
-                    self.add_error("cannot convert centimeters to degrees");
-                    n
+    fn config_entry(&mut self) {
+        let mut words = Vec::new();
+        loop {
+            let word = match self.peek() {
+                Some(Token::Ident(word)) if !word.eq_ignore_ascii_case("port") => {
+                    word.to_ascii_lowercase()
                 }
-                NumberSuffix::In => {
-                    self.add_error("cannot convert inches to degrees");
-                    n
+                Some(Token::Int(tok)) if *tok != IntToken::End => tok.name().to_string(),
+                _ => break,
+            };
+            self.next_token();
+            words.push(word);
+            if CONFIG_KEYS.contains(&words.join(" ").as_str()) {
+                break;
+            }
+        }
+        let key = words.join(" ");
+
+        match key.as_str() {
+            "left motor" | "right motor" | "front arm" | "back arm" | "color sensor"
+            | "distance sensor" | "force sensor" => {
+                if matches!(self.peek(), Some(Token::Ident(word)) if word.eq_ignore_ascii_case("port"))
+                {
+                    self.next_token();
+                }
+                let letter = match self.peek() {
+                    Some(Token::Ident(name)) => Some(name.clone()),
+                    _ => None,
+                };
+                let Some(letter) = letter else {
+                    self.add_error(
+                        Code::ExpectedToken,
+                        format!("expected a port from A to F after \"{key}\""),
+                    );
+                    self.skip_line();
+                    return;
+                };
+                self.next_token();
+                let Some(port) = port(&letter) else {
+                    self.add_error(
+                        Code::InvalidValue,
+                        format!(
+                            "invalid port \"{letter}\" for \"{key}\", expected a port from A to F"
+                        ),
+                    );
+                    self.skip_line();
+                    return;
+                };
+                if let Some((other, _)) = self
+                    .config
+                    .ports()
+                    .into_iter()
+                    .find(|(name, other)| *name != key && *other == Some(port))
+                {
+                    self.add_error(
+                        Code::InvalidValue,
+                        format!("\"{key}\" cannot use port {port:?}, \"{other}\" already uses it"),
+                    );
+                    self.skip_line();
+                    return;
+                }
+                let slot = match key.as_str() {
+                    "left motor" => &mut self.config.left_motor,
+                    "right motor" => &mut self.config.right_motor,
+                    "front arm" => &mut self.config.front_arm,
+                    "back arm" => &mut self.config.back_arm,
+                    "color sensor" => &mut self.config.color_sensor,
+                    "distance sensor" => &mut self.config.distance_sensor,
+                    _ => &mut self.config.force_sensor,
+                };
+                *slot = Some(port);
+            }
+            "wheel circumference" | "track width" | "max speed" => {
+                let default = if key == "max speed" {
+                    NumberSuffix::Dps
+                } else {
+                    NumberSuffix::Cm
+                };
+                let value = match self.quantity(default) {
+                    Ok(Quantity {
+                        value: Expr::Number(n),
+                        ..
+                    }) if n > 0.0 => n,
+                    Ok(_) => {
+                        self.add_error(
+                            Code::InvalidValue,
+                            format!("\"{key}\" must be a positive constant"),
+                        );
+                        self.skip_line();
+                        return;
+                    }
+                    Err(error) => {
+                        self.missing::<()>(error, format!("expected a value after \"{key}\""));
+                        self.skip_line();
+                        return;
+                    }
+                };
+                match key.as_str() {
+                    "wheel circumference" => self.config.wheel_circumference = Some(value),
+                    "track width" => self.config.track_width = Some(value),
+                    _ => self.config.max_speed = Some(value),
+                }
+            }
+            "" => {
+                self.add_error(Code::ExpectedToken, "expected a config key");
+                self.skip_line();
+                return;
+            }
+            _ => {
+                self.add_error(Code::InvalidValue, format!("unknown config key \"{key}\""));
+                self.skip_line();
+                return;
+            }
+        }
+
+        if !matches!(
+            self.peek(),
+            Some(Token::Lf | Token::Errors(_) | Token::Int(IntToken::End)) | None
+        ) {
+            self.add_error(
+                Code::UnexpectedToken,
+                format!("unexpected text after \"{key}\", ignoring"),
+            );
+            self.skip_line();
+        }
+    }
+
+    fn repeat(&mut self) -> Option<Statement> {
+        let (count, dimension) = match self.number() {
+            Ok((count, dimension)) => (count, dimension),
+            Err(error) => return self.missing(error, "expected number after \"repeat\". ignoring"),
+        };
+        if self.known(dimension, None).is_some() {
+            self.add_error(Code::UnitMismatch, "repeat count cannot have a unit");
+        }
+        if let Expr::Number(n) = count
+            && (n < 0.0 || n.fract() != 0.0)
+        {
+            self.add_error(
+                Code::InvalidValue,
+                format!("repeat count must be a whole number, got {n}"),
+            );
+        }
+
+        if matches!(self.peek(), Some(Token::Int(IntToken::Times))) {
+            self.next_token();
+        } else {
+            self.add_error(Code::ExpectedToken, "expected \"times\" after repeat count");
+        }
+
+        Some(Statement::Repeat(count, self.block("repeat")))
+    }
+
+    fn together(&mut self) -> Option<Statement> {
+        let mut commands = Vec::new();
+        for statement in self.block("together") {
+            match statement.node {
+                Statement::Command(command) if command.is_action() => commands.push(command),
+                Statement::Command(Command::Nop) => {}
+                _ => self.add_error_at(
+                    Code::Misplaced,
+                    statement.span,
+                    "only fixed motor commands, \"wait\" and \"beep\" can run inside \"together\". ignoring",
+                ),
+            }
+        }
+        Some(Statement::Together(commands))
+    }
+
+    fn assignment(&mut self) -> Option<Statement> {
+        let name = match self.peek() {
+            Some(Token::Ident(name)) => name.clone(),
+            _ => {
+                self.add_error(
+                    Code::ExpectedToken,
+                    "expected variable name after \"let\". ignoring",
+                );
+                return None;
+            }
+        };
+        self.next_token();
+
+        if matches!(self.peek(), Some(Token::Op('='))) {
+            self.next_token();
+        } else {
+            self.add_error(
+                Code::ExpectedToken,
+                format!("expected \"=\" after \"let {name}\""),
+            );
+        }
+
+        let (value, dimension) = match self.number() {
+            Ok((value, dimension)) => (value, dimension),
+            Err(error) => {
+                return self.missing(error, format!("expected value for \"{name}\". ignoring"));
+            }
+        };
+        self.variables.insert(name.clone(), dimension);
+        Some(Statement::Let(name, value))
+    }
+
+    fn curve(&mut self, token: IntToken, amount: Quantity) -> Option<Statement> {
+        if !self.eat(IntToken::Radius) {
+            self.add_error(
+                Code::ExpectedToken,
+                format!("expected \"radius\" after \"{}\"", token.name()),
+            );
+            self.skip_line();
+            return None;
+        }
+        let radius = match self.cm() {
+            Ok(radius) => radius,
+            Err(error) => return self.missing(error, "expected number after \"radius\". ignoring"),
+        };
+        if let Expr::Number(r) = radius.value
+            && r <= 0.0
+        {
+            self.add_error(
+                Code::InvalidValue,
+                format!("curve radius must be positive, got {r}"),
+            );
+        }
+        let speed = self.speed();
+
+        let command = if token == IntToken::CurveLeft {
+            Command::CurveLeft {
+                amount,
+                radius,
+                speed,
+            }
+        } else {
+            Command::CurveRight {
+                amount,
+                radius,
+                speed,
+            }
+        };
+        Some(Statement::Command(command))
+    }
+
+    fn beep(&mut self) -> Option<Statement> {
+        let frequency = match self.hertz() {
+            Ok(frequency) => frequency,
+            Err(error) => {
+                return self.missing(error, "expected frequency after \"beep\". ignoring");
+            }
+        };
+        if let Expr::Number(f) = frequency.value
+            && f <= 0.0
+        {
+            self.add_error(
+                Code::InvalidValue,
+                format!("beep frequency must be positive, got {f} Hz"),
+            );
+        }
+
+        let duration = if self.eat(IntToken::For) {
+            match self.ms() {
+                Ok(duration) => {
+                    if let Expr::Number(n) = duration.value
+                        && n < 0.0
+                    {
+                        self.add_error(
+                            Code::InvalidValue,
+                            format!("cannot beep for a negative time, got {n}"),
+                        );
+                    }
+                    Some(duration)
                 }
-                NumberSuffix::Deg => n,
-                NumberSuffix::Rad => n * (180.0 / PI),
+                Err(error) => self.missing(error, "expected duration after \"for\""),
+            }
+        } else {
+            None
+        };
+        Some(Statement::Command(Command::Beep {
+            frequency,
+            duration,
+        }))
+    }
+
+    fn until(&mut self, token: IntToken) -> Option<Statement> {
+        let Some(condition) = self.condition() else {
+            self.skip_line();
+            return None;
+        };
+
+        let max = if self.eat(IntToken::Max) {
+            let max = match token {
+                IntToken::MoveForward | IntToken::MoveBackward => self.cm(),
+                _ => self.deg(),
             };
-            Some(number)
+            match max {
+                Ok(max) => Some(max),
+                Err(error) => self.missing(error, "expected number after \"max\""),
+            }
         } else {
             None
+        };
+        let speed = self.speed();
+
+        let command = match token {
+            IntToken::MoveForward => Command::MoveForwardUntil {
+                condition,
+                max,
+                speed,
+            },
+            IntToken::MoveBackward => Command::MoveBackwardUntil {
+                condition,
+                max,
+                speed,
+            },
+            IntToken::RotateRight => Command::RotateRightUntil {
+                condition,
+                max,
+                speed,
+            },
+            IntToken::RotateLeft => Command::RotateLeftUntil {
+                condition,
+                max,
+                speed,
+            },
+            _ => unreachable!(),
+        };
+        Some(Statement::Command(command))
+    }
+
+    fn conditional(&mut self) -> Option<Statement> {
+        let condition = self.condition();
+        if condition.is_none() {
+            self.skip_line();
+        }
+
+        let then = self.block("if");
+        let otherwise = if self.eat(IntToken::Else) {
+            self.block("else")
+        } else {
+            Vec::new()
+        };
+
+        condition.map(|condition| Statement::If(condition, then, otherwise))
+    }
+
+    fn condition(&mut self) -> Option<Condition> {
+        let mut lhs = self.conjunction()?;
+        while self.eat(IntToken::Or) {
+            let rhs = self.conjunction()?;
+            lhs = Condition::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Some(lhs)
+    }
+
+    fn conjunction(&mut self) -> Option<Condition> {
+        let mut lhs = self.negation()?;
+        while self.eat(IntToken::And) {
+            let rhs = self.negation()?;
+            lhs = Condition::And(Box::new(lhs), Box::new(rhs));
+        }
+        Some(lhs)
+    }
+
+    fn negation(&mut self) -> Option<Condition> {
+        if self.eat(IntToken::Not) {
+            Some(Condition::Not(Box::new(self.negation()?)))
+        } else {
+            self.sensor()
+        }
+    }
+
+    fn sensor(&mut self) -> Option<Condition> {
+        let sensor = match self.peek() {
+            Some(Token::Int(tok @ (IntToken::Color | IntToken::Distance | IntToken::Force))) => {
+                *tok
+            }
+            _ => {
+                self.add_error(
+                    Code::ExpectedToken,
+                    "expected \"color\", \"distance\" or \"force\" condition",
+                );
+                return None;
+            }
+        };
+        self.next_token();
+        let port = self.port();
+
+        match sensor {
+            IntToken::Color => {
+                if !self.eat(IntToken::Is) {
+                    self.add_error(Code::ExpectedToken, "expected \"is\" after \"color\"");
+                    return None;
+                }
+                let negated = self.eat(IntToken::Not);
+                let color = match self.peek() {
+                    Some(Token::Int(tok)) => color(*tok),
+                    _ => None,
+                };
+                let Some(color) = color else {
+                    self.add_error(Code::ExpectedToken, "expected a color after \"color is\"");
+                    return None;
+                };
+                self.next_token();
+
+                let condition = Condition::Color(port, color);
+                if negated {
+                    Some(Condition::Not(Box::new(condition)))
+                } else {
+                    Some(condition)
+                }
+            }
+            IntToken::Distance => {
+                self.eat(IntToken::Is);
+                let comparison = self.comparison()?;
+                let cm = match self.cm() {
+                    Ok(cm) => cm,
+                    Err(error) => {
+                        return self.missing(error, "expected number after distance comparison");
+                    }
+                };
+                Some(Condition::Distance(port, comparison, cm))
+            }
+            _ => {
+                if self.eat(IntToken::Is) {
+                    let negated = self.eat(IntToken::Not);
+                    if self.eat(IntToken::Pressed) {
+                        let condition = Condition::Pressed(port);
+                        return if negated {
+                            Some(Condition::Not(Box::new(condition)))
+                        } else {
+                            Some(condition)
+                        };
+                    } else if negated {
+                        self.add_error(
+                            Code::ExpectedToken,
+                            "expected \"pressed\" after \"force is not\"",
+                        );
+                        return None;
+                    }
+                }
+                let comparison = self.comparison()?;
+                let newtons = match self.newtons() {
+                    Ok(newtons) => newtons,
+                    Err(error) => {
+                        return self.missing(error, "expected number after force comparison");
+                    }
+                };
+                Some(Condition::Force(port, comparison, newtons))
+            }
         }
     }
 
-    fn number(&mut self) -> Option<(f32, Option<NumberSuffix>)> {
-        if self.is_eof() {
+    fn port(&mut self) -> Option<Port> {
+        if !self.eat(IntToken::On) {
             return None;
         }
 
-        let token = self.next_token().unwrap();
+        let port = match self.peek() {
+            Some(Token::Ident(name)) => port(name),
+            _ => None,
+        };
+        if port.is_some() {
+            self.next_token();
+        } else {
+            self.add_error(
+                Code::ExpectedToken,
+                "expected a port from A to F after \"on\"",
+            );
+        }
+        port
+    }
 
-        match token {
-            Token::Lf => self.number(),
-            Token::Number(i) => Some((i, self.number_suffix())),
-            Token::Int(_) => None,
-            Token::Errors(mut i) => {
-                self.errors.append(&mut i);
-                None
+    fn comparison(&mut self) -> Option<Comparison> {
+        let comparison = match self.peek() {
+            Some(Token::Op('<') | Token::Int(IntToken::Under)) => Comparison::Less,
+            Some(Token::Op('>') | Token::Int(IntToken::Over)) => Comparison::Greater,
+            _ => {
+                self.add_error(
+                    Code::ExpectedToken,
+                    "expected \"<\", \">\", \"under\" or \"over\"",
+                );
+                return None;
+            }
+        };
+        self.next_token();
+        Some(comparison)
+    }
+
+    fn block(&mut self, name: &str) -> Vec<Spanned<Statement>> {
+        let open = self.start;
+        let mut statements = Vec::new();
+        self.depth += 1;
+        loop {
+            match self.peek() {
+                Some(Token::Lf) => {
+                    self.next_token();
+                }
+                Some(Token::Int(IntToken::End)) => {
+                    self.next_token();
+                    break;
+                }
+                Some(Token::Int(IntToken::Else)) if name == "if" => break,
+                Some(Token::Int(IntToken::Else)) => {
+                    self.next_token();
+                    self.add_error(Code::UnexpectedToken, "unexpected \"else\", ignoring");
+                }
+                Some(Token::Errors(_)) | None => {
+                    self.report(
+                        Diagnostic::error(
+                            Code::ExpectedToken,
+                            self.span,
+                            format!("expected \"end\" to close \"{name}\""),
+                        )
+                        .with_label(open, format!("\"{name}\" starts here")),
+                    );
+                    break;
+                }
+                Some(_) => {
+                    if let Some(statement) = self.command() {
+                        statements.push(statement);
+                    }
+                }
             }
         }
+        self.depth -= 1;
+        statements
     }
 
-    fn number_suffix(&mut self) -> Option<NumberSuffix> {
-        if self.is_eof() {
+    fn cm(&mut self) -> Result<Quantity, NoValue> {
+        self.quantity(NumberSuffix::Cm)
+    }
+
+    fn deg(&mut self) -> Result<Quantity, NoValue> {
+        self.quantity(NumberSuffix::Deg)
+    }
+
+    fn ms(&mut self) -> Result<Quantity, NoValue> {
+        self.quantity(NumberSuffix::S)
+    }
+
+    fn newtons(&mut self) -> Result<Quantity, NoValue> {
+        self.quantity(NumberSuffix::N)
+    }
+
+    fn hertz(&mut self) -> Result<Quantity, NoValue> {
+        self.quantity(NumberSuffix::Hz)
+    }
+
+    fn quantity(&mut self, default: NumberSuffix) -> Result<Quantity, NoValue> {
+        let (value, dimension) = self.number()?;
+        let expected = default.dimension();
+        match self.known(dimension, Some(expected)) {
+            None => Ok(Quantity {
+                value: convert(value, default),
+                dimension: expected,
+            }),
+            Some(dimension) if dimension == expected => Ok(Quantity { value, dimension }),
+            Some(dimension) => {
+                self.add_error(
+                    Code::UnitMismatch,
+                    format!("expected {}, found {}", expected.name(), dimension.name()),
+                );
+                Err(NoValue::Reported)
+            }
+        }
+    }
+
+    fn percent(&mut self) -> Result<Quantity, NoValue> {
+        let (value, dimension) = self.number()?;
+        match self
+            .known(dimension, Some(Dimension::Percent))
+            .unwrap_or(Dimension::Percent)
+        {
+            Dimension::Percent => {
+                if let Expr::Number(n) = value
+                    && !(0.0..=100.0).contains(&n)
+                {
+                    self.add_error(
+                        Code::InvalidValue,
+                        format!("speed must be between 0% and 100%, got {n}%"),
+                    );
+                }
+                Ok(Quantity {
+                    value,
+                    dimension: Dimension::Percent,
+                })
+            }
+            Dimension::Speed => Ok(Quantity {
+                value,
+                dimension: Dimension::Speed,
+            }),
+            dimension => {
+                self.add_error(
+                    Code::UnitMismatch,
+                    format!("expected a speed, found {}", dimension.name()),
+                );
+                Err(NoValue::Reported)
+            }
+        }
+    }
+
+    fn speed(&mut self) -> Option<Quantity> {
+        if !matches!(self.peek(), Some(Token::Int(IntToken::At))) {
             return None;
         }
+        self.next_token();
 
-        let peek = self.lexer.peek();
-        let token = peek.get().unwrap();
+        match self.percent() {
+            Ok(speed) => Some(speed),
+            Err(error) => self.missing(error, "expected speed after \"at\""),
+        }
+    }
 
-        match token {
-            Token::Lf => {
+    fn steering(&mut self) -> Option<Expr> {
+        if !self.eat(IntToken::Steering) {
+            return None;
+        }
+
+        let (n, dimension) = match self.number() {
+            Ok((n, dimension)) => (n, dimension),
+            Err(error) => return self.missing(error, "expected number after \"steering\""),
+        };
+        if let Some(dimension) = self.known(dimension, None) {
+            self.add_error(
+                Code::UnitMismatch,
+                format!(
+                    "steering must be a plain number, found {}",
+                    dimension.name()
+                ),
+            );
+            return None;
+        }
+        if let Expr::Number(n) = n
+            && !(-100.0..=100.0).contains(&n)
+        {
+            self.add_error(
+                Code::InvalidValue,
+                format!("steering must be between -100 and 100, got {n}"),
+            );
+        }
+        Some(n)
+    }
+
+    fn number(&mut self) -> Result<(Expr, Dim), NoValue> {
+        if !matches!(
+            self.peek(),
+            Some(Token::Number(_) | Token::Ident(_) | Token::Op('(' | '+' | '-'))
+        ) {
+            return Err(NoValue::Missing);
+        }
+
+        self.expr().ok_or(NoValue::Reported)
+    }
+
+    fn known(&mut self, dim: Dim, expected: Option<Dimension>) -> Option<Dimension> {
+        match dim {
+            Dim::Known(dimension) => dimension,
+            Dim::Param(i) => match self.params[i] {
+                Dim::Known(dimension) => dimension,
+                Dim::Param(_) => {
+                    self.params[i] = Dim::Known(expected);
+                    expected
+                }
+            },
+        }
+    }
+
+    fn expr(&mut self) -> Option<(Expr, Dim)> {
+        let (mut lhs, mut dim) = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op('+')) => BinOp::Add,
+                Some(Token::Op('-')) => BinOp::Sub,
+                _ => return Some((lhs, dim)),
+            };
+            self.next_token();
+            let (rhs, r) = self.term()?;
+            dim = self.combine(dim, op, r)?;
+            lhs = self.binary(lhs, op, rhs);
+        }
+    }
+
+    fn term(&mut self) -> Option<(Expr, Dim)> {
+        let (mut lhs, mut dim) = self.atom()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op('*')) => BinOp::Mul,
+                Some(Token::Op('/')) => BinOp::Div,
+                _ => return Some((lhs, dim)),
+            };
+            self.next_token();
+            let (rhs, r) = self.atom()?;
+            dim = self.combine(dim, op, r)?;
+            lhs = self.binary(lhs, op, rhs);
+        }
+    }
+
+    fn atom(&mut self) -> Option<(Expr, Dim)> {
+        let (expr, dim) = self.operand()?;
+        let Some(suffix) = self.number_suffix() else {
+            return Some((expr, dim));
+        };
+        match self.known(dim, None) {
+            Some(dimension) => {
+                self.add_error(
+                    Code::UnitMismatch,
+                    format!(
+                        "cannot use {} on a value that is already {}",
+                        suffix.name(),
+                        dimension.name()
+                    ),
+                );
+                None
+            }
+            None => Some((convert(expr, suffix), Dim::Known(Some(suffix.dimension())))),
+        }
+    }
+
+    fn operand(&mut self) -> Option<(Expr, Dim)> {
+        match self.peek() {
+            Some(Token::Number(n)) => {
+                let n = *n;
                 self.next_token();
-                self.number_suffix()
+                Some((Expr::Number(n), Dim::Known(None)))
+            }
+            Some(Token::Ident(name)) => {
+                let name = name.clone();
+                self.next_token();
+                let dim = match self.variables.get(&name) {
+                    Some(Dim::Param(i)) => self.params[*i],
+                    Some(dim) => *dim,
+                    None => {
+                        self.add_error(
+                            Code::UndefinedVariable,
+                            format!("use of undefined variable \"{name}\""),
+                        );
+                        Dim::Known(None)
+                    }
+                };
+                Some((Expr::Var(name), dim))
             }
-            Token::Number(_) => None,
+            Some(Token::Op('+')) => {
+                self.next_token();
+                self.atom()
+            }
+            Some(Token::Op('-')) => {
+                self.next_token();
+                let (expr, dim) = self.atom()?;
+                let expr = match expr {
+                    Expr::Number(n) => Expr::Number(-n),
+                    Expr::Neg(expr) => *expr,
+                    expr => Expr::Neg(Box::new(expr)),
+                };
+                Some((expr, dim))
+            }
+            Some(Token::Op('(')) => {
+                self.next_token();
+                let expr = self.expr()?;
+                if !self.eat_op(')') {
+                    self.add_error(Code::ExpectedToken, "expected \")\" to close \"(\"");
+                }
+                Some(expr)
+            }
+            _ => {
+                self.add_error(Code::ExpectedToken, "expected a number, variable or \"(\"");
+                None
+            }
+        }
+    }
+
+    fn combine(&mut self, lhs: Dim, op: BinOp, rhs: Dim) -> Option<Dim> {
+        let additive = matches!(op, BinOp::Add | BinOp::Sub);
+        match (lhs, rhs) {
+            (Dim::Param(i), Dim::Param(j)) if additive && i == j => return Some(lhs),
+            (Dim::Param(_), Dim::Known(None)) if !additive => return Some(lhs),
+            (Dim::Known(None), Dim::Param(_)) if op == BinOp::Mul => return Some(rhs),
+            _ => {}
+        }
+
+        let expected = |other: Dim| match other {
+            Dim::Known(dimension) if additive => dimension,
+            _ => None,
+        };
+        let l = self.known(lhs, expected(rhs));
+        let r = self.known(rhs, expected(lhs));
+        match (op, l, r) {
+            (BinOp::Add | BinOp::Sub, l, r) if l == r => Some(Dim::Known(l)),
+            (BinOp::Mul, l, None) | (BinOp::Mul, None, l) | (BinOp::Div, l, None) => {
+                Some(Dim::Known(l))
+            }
+            (BinOp::Div, Some(l), Some(r)) if l == r => Some(Dim::Known(None)),
+            _ => {
+                let name = |d: Option<Dimension>| d.map_or("a plain number", Dimension::name);
+                let verb = match op {
+                    BinOp::Add => "add",
+                    BinOp::Sub => "subtract",
+                    BinOp::Mul => "multiply",
+                    BinOp::Div => "divide",
+                };
+                self.add_error(
+                    Code::UnitMismatch,
+                    format!("cannot {verb} {} and {}", name(l), name(r)),
+                );
+                None
+            }
+        }
+    }
+
+    fn binary(&mut self, lhs: Expr, op: BinOp, rhs: Expr) -> Expr {
+        match (&lhs, &rhs) {
+            (Expr::Number(_), Expr::Number(r)) if op == BinOp::Div && *r == 0.0 => {
+                self.add_error(Code::InvalidValue, "division by zero");
+            }
+            (Expr::Number(l), Expr::Number(r)) => {
+                return Expr::Number(match op {
+                    BinOp::Add => l + r,
+                    BinOp::Sub => l - r,
+                    BinOp::Mul => l * r,
+                    BinOp::Div => l / r,
+                });
+            }
+            _ => {}
+        }
+        Expr::Binary(Box::new(lhs), op, Box::new(rhs))
+    }
+
+    fn number_suffix(&mut self) -> Option<NumberSuffix> {
+        match self.peek()? {
+            Token::Lf | Token::Number(_) | Token::Ident(_) | Token::Str(_) | Token::Op(_) => None,
             Token::Int(int_token) => match int_token {
                 IntToken::Cm => {
-                    self.lexer.next();
+                    self.next_token();
                     Some(NumberSuffix::Cm)
                 }
                 IntToken::Rad => {
-                    self.lexer.next();
+                    self.next_token();
                     Some(NumberSuffix::Rad)
                 }
                 IntToken::Deg => {
-                    self.lexer.next();
+                    self.next_token();
                     Some(NumberSuffix::Deg)
                 }
                 IntToken::In => {
-                    self.lexer.next();
+                    self.next_token();
                     Some(NumberSuffix::In)
                 }
+                IntToken::S => {
+                    self.next_token();
+                    Some(NumberSuffix::S)
+                }
+                IntToken::Ms => {
+                    self.next_token();
+                    Some(NumberSuffix::Ms)
+                }
+                IntToken::Min => {
+                    self.next_token();
+                    Some(NumberSuffix::Min)
+                }
+                IntToken::Percent => {
+                    self.next_token();
+                    Some(NumberSuffix::Percent)
+                }
+                IntToken::Newton => {
+                    self.next_token();
+                    Some(NumberSuffix::N)
+                }
+                IntToken::Hz => {
+                    self.next_token();
+                    Some(NumberSuffix::Hz)
+                }
+                IntToken::Khz => {
+                    self.next_token();
+                    Some(NumberSuffix::KHz)
+                }
+                IntToken::Mm => {
+                    self.next_token();
+                    Some(NumberSuffix::Mm)
+                }
+                IntToken::M => {
+                    self.next_token();
+                    Some(NumberSuffix::M)
+                }
+                IntToken::Ft => {
+                    self.next_token();
+                    Some(NumberSuffix::Ft)
+                }
+                IntToken::Rotations => {
+                    self.next_token();
+                    Some(NumberSuffix::Rotations)
+                }
+                IntToken::WheelRotations => {
+                    self.next_token();
+                    Some(NumberSuffix::WheelRotations)
+                }
+                IntToken::Dps => {
+                    self.next_token();
+                    Some(NumberSuffix::Dps)
+                }
+                IntToken::Rpm => {
+                    self.next_token();
+                    Some(NumberSuffix::Rpm)
+                }
+                IntToken::CmPerS => {
+                    self.next_token();
+                    Some(NumberSuffix::CmPerS)
+                }
                 _ => None,
             },
             Token::Errors(_) => {
-                if let Some(Token::Errors(mut i)) = self.lexer.next() {
-                    self.errors.append(&mut i);
+                if let Some(Token::Errors(mut i)) = self.next_token() {
+                    self.diagnostics.append(&mut i);
                     None
                 } else {
                     unreachable!()
@@ -241,14 +1768,661 @@ impl<'src> Parser<'src> {
         }
     }
 
-    pub fn finish(self) -> Vec<String> {
-        self.errors
+    pub fn finish(mut self, mut script: Vec<Spanned<Statement>>) -> (Program, Vec<Diagnostic>) {
+        let indices = self
+            .procedures
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.name.as_str(), i))
+            .collect::<HashMap<_, _>>();
+
+        let mut errors = Vec::new();
+        let mut conversions = HashMap::new();
+        for call in &self.calls {
+            let name = &call.name;
+            let Some(&i) = indices.get(name.as_str()) else {
+                errors.push(
+                    Diagnostic::error(
+                        Code::UndefinedProcedure,
+                        call.span,
+                        format!("call to undefined procedure \"{name}\""),
+                    )
+                    .with_help(format!("define it with \"define {name}\" ... \"end\"")),
+                );
+                continue;
+            };
+            let procedure = &self.procedures[i];
+            if let Err(error) = check_arity(call.span, procedure, call.args.len()) {
+                errors.push(error);
+                continue;
+            }
+
+            let mut suffixes = Vec::new();
+            for (n, (arg, param)) in call.args.iter().zip(&procedure.params).enumerate() {
+                let arg = match *arg {
+                    Arg::Known(dimension) => dimension,
+                    Arg::Param(procedure, i) => self.procedures[procedure].params[i].dimension,
+                    Arg::Any => param.dimension,
+                };
+                match check_argument(name, n, arg, param.dimension) {
+                    Ok(suffix) => suffixes.push(suffix),
+                    Err(message) => errors.push(call_error(call.span, procedure, message)),
+                }
+            }
+            if suffixes.iter().any(Option::is_some) {
+                conversions.insert((call.span.file, call.span.start), suffixes);
+            }
+        }
+
+        let mut state = vec![Visit::New; self.procedures.len()];
+        for i in 0..self.procedures.len() {
+            find_recursion(&self.procedures, &indices, i, &mut state, &mut errors);
+        }
+
+        if !conversions.is_empty() {
+            convert_calls(&mut script, &conversions);
+            for procedure in &mut self.procedures {
+                convert_calls(&mut procedure.body, &conversions);
+            }
+            for mission in &mut self.missions {
+                convert_calls(&mut mission.body, &conversions);
+            }
+        }
+
+        self.diagnostics.append(&mut errors);
+        let program = Program {
+            config: self.config,
+            procedures: self.procedures,
+            missions: self.missions,
+            script,
+        };
+        (program, self.diagnostics)
+    }
+}
+
+fn collect_calls<'a>(code: &'a [Spanned<Statement>], calls: &mut Vec<&'a str>) {
+    for i in code {
+        match &i.node {
+            Statement::Call(name, _) => calls.push(name),
+            Statement::Repeat(_, body) => collect_calls(body, calls),
+            Statement::If(_, then, otherwise) => {
+                collect_calls(then, calls);
+                collect_calls(otherwise, calls);
+            }
+            Statement::Command(_) | Statement::Let(..) | Statement::Together(_) => {}
+        }
+    }
+}
+
+fn convert_calls(
+    code: &mut [Spanned<Statement>],
+    conversions: &HashMap<(usize, usize), Vec<Option<NumberSuffix>>>,
+) {
+    for i in code {
+        match &mut i.node {
+            Statement::Call(_, args) => {
+                let Some(suffixes) = conversions.get(&(i.span.file, i.span.start)) else {
+                    continue;
+                };
+                for (arg, suffix) in args.iter_mut().zip(suffixes) {
+                    if let Some(suffix) = *suffix {
+                        *arg = convert(mem::replace(arg, Expr::Number(0.0)), suffix);
+                    }
+                }
+            }
+            Statement::Repeat(_, body) => convert_calls(body, conversions),
+            Statement::If(_, then, otherwise) => {
+                convert_calls(then, conversions);
+                convert_calls(otherwise, conversions);
+            }
+            Statement::Command(_) | Statement::Let(..) | Statement::Together(_) => {}
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    New,
+    Active,
+    Done,
+}
+
+fn find_recursion(
+    procedures: &[Procedure],
+    indices: &HashMap<&str, usize>,
+    i: usize,
+    state: &mut [Visit],
+    errors: &mut Vec<Diagnostic>,
+) {
+    if state[i] != Visit::New {
+        return;
+    }
+
+    let callees = |i: usize| {
+        let mut calls = Vec::new();
+        collect_calls(&procedures[i].body, &mut calls);
+        calls
+            .into_iter()
+            .rev()
+            .filter_map(|name| indices.get(name).copied())
+            .collect::<Vec<_>>()
+    };
+
+    state[i] = Visit::Active;
+    let mut stack = vec![(i, callees(i))];
+    while let Some((caller, calls)) = stack.last_mut() {
+        let Some(callee) = calls.pop() else {
+            state[*caller] = Visit::Done;
+            stack.pop();
+            continue;
+        };
+        match state[callee] {
+            Visit::Done => {}
+            Visit::Active => {
+                let start = stack.iter().position(|(i, _)| *i == callee).unwrap();
+                let mut cycle = stack[start..]
+                    .iter()
+                    .map(|(i, _)| procedures[*i].name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                cycle += " -> ";
+                cycle += &procedures[callee].name;
+                errors.push(Diagnostic::error(
+                    Code::Recursion,
+                    procedures[callee].span,
+                    format!("recursive call: {cycle}"),
+                ));
+            }
+            Visit::New => {
+                state[callee] = Visit::Active;
+                stack.push((callee, callees(callee)));
+            }
+        }
+    }
+}
+
+fn port(name: &str) -> Option<Port> {
+    match name.to_ascii_uppercase().as_str() {
+        "A" => Some(Port::A),
+        "B" => Some(Port::B),
+        "C" => Some(Port::C),
+        "D" => Some(Port::D),
+        "E" => Some(Port::E),
+        "F" => Some(Port::F),
+        _ => None,
+    }
+}
+
+fn color(token: IntToken) -> Option<Color> {
+    match token {
+        IntToken::Black => Some(Color::Black),
+        IntToken::Magenta => Some(Color::Magenta),
+        IntToken::Purple => Some(Color::Purple),
+        IntToken::Blue => Some(Color::Blue),
+        IntToken::Azure => Some(Color::Azure),
+        IntToken::Turquoise => Some(Color::Turquoise),
+        IntToken::Green => Some(Color::Green),
+        IntToken::Yellow => Some(Color::Yellow),
+        IntToken::Orange => Some(Color::Orange),
+        IntToken::Red => Some(Color::Red),
+        IntToken::White => Some(Color::White),
+        _ => None,
+    }
+}
+
+const CONFIG_KEYS: &[&str] = &[
+    "left motor",
+    "right motor",
+    "front arm",
+    "back arm",
+    "color sensor",
+    "distance sensor",
+    "force sensor",
+    "wheel circumference",
+    "track width",
+    "max speed",
+];
+
+const IMAGES: &[&str] = &[
+    "ANGRY",
+    "ARROW_E",
+    "ARROW_N",
+    "ARROW_NE",
+    "ARROW_NW",
+    "ARROW_S",
+    "ARROW_SE",
+    "ARROW_SW",
+    "ARROW_W",
+    "ASLEEP",
+    "BUTTERFLY",
+    "CHESSBOARD",
+    "CONFUSED",
+    "COW",
+    "DIAMOND",
+    "DIAMOND_SMALL",
+    "DUCK",
+    "FABULOUS",
+    "GHOST",
+    "GIRAFFE",
+    "GO_DOWN",
+    "GO_LEFT",
+    "GO_RIGHT",
+    "GO_UP",
+    "HAPPY",
+    "HEART",
+    "HEART_SMALL",
+    "HOUSE",
+    "MEH",
+    "MUSIC_CROTCHET",
+    "MUSIC_QUAVER",
+    "MUSIC_QUAVERS",
+    "NO",
+    "PACMAN",
+    "PITCHFORK",
+    "RABBIT",
+    "ROLLERSKATE",
+    "SAD",
+    "SILLY",
+    "SKULL",
+    "SMILE",
+    "SNAKE",
+    "SQUARE",
+    "SQUARE_SMALL",
+    "STICKFIGURE",
+    "SURPRISED",
+    "SWORD",
+    "TARGET",
+    "TORTOISE",
+    "TRIANGLE",
+    "TRIANGLE_LEFT",
+    "TSHIRT",
+    "UMBRELLA",
+    "XMAS",
+    "YES",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Dim {
+    Known(Option<Dimension>),
+    Param(usize),
+}
+
+enum NoValue {
+    Missing,
+    Reported,
+}
+
+struct Call {
+    name: String,
+    span: Span,
+    args: Vec<Arg>,
+}
+
+#[derive(Clone, Copy)]
+enum Arg {
+    Known(Option<Dimension>),
+    Param(usize, usize),
+    Any,
+}
+
+fn call_error(span: Span, procedure: &Procedure, message: impl ToString) -> Diagnostic {
+    Diagnostic::error(Code::ArgumentMismatch, span, message)
+        .with_label(procedure.span, "defined here")
+}
+
+fn check_arity(span: Span, procedure: &Procedure, args: usize) -> Result<(), Diagnostic> {
+    let params = procedure.params.len();
+    if params == args {
+        return Ok(());
+    }
+    Err(call_error(
+        span,
+        procedure,
+        format!(
+            "\"{}\" expects {params} argument{}, got {args}",
+            procedure.name,
+            if params == 1 { "" } else { "s" }
+        ),
+    ))
+}
+
+fn check_argument(
+    name: &str,
+    i: usize,
+    arg: Option<Dimension>,
+    param: Option<Dimension>,
+) -> Result<Option<NumberSuffix>, String> {
+    match (arg, param) {
+        (arg, param) if arg == param => Ok(None),
+        (None, Some(param)) => Ok(Some(default_suffix(param))),
+        (Some(arg), None) => Err(format!(
+            "argument {} of \"{name}\" must be a plain number, found {}",
+            i + 1,
+            arg.name()
+        )),
+        (Some(arg), Some(param)) => Err(format!(
+            "argument {} of \"{name}\" expects {}, found {}",
+            i + 1,
+            param.name(),
+            arg.name()
+        )),
+        (None, None) => unreachable!(),
+    }
+}
+
+fn convert(value: Expr, suffix: NumberSuffix) -> Expr {
+    match (value, suffix.factor()) {
+        (value, Expr::Number(1.0)) => value,
+        (Expr::Number(value), Expr::Number(factor)) => Expr::Number(value * factor),
+        (value, factor) => Expr::Binary(Box::new(value), BinOp::Mul, Box::new(factor)),
     }
 }
 
+fn default_suffix(dimension: Dimension) -> NumberSuffix {
+    match dimension {
+        Dimension::Length => NumberSuffix::Cm,
+        Dimension::Angle => NumberSuffix::Deg,
+        Dimension::Time => NumberSuffix::S,
+        Dimension::Speed => NumberSuffix::Dps,
+        Dimension::Percent => NumberSuffix::Percent,
+        Dimension::Force => NumberSuffix::N,
+        Dimension::Frequency => NumberSuffix::Hz,
+    }
+}
+
+#[derive(Clone, Copy)]
 enum NumberSuffix {
     Cm,
+    Mm,
+    M,
     In,
+    Ft,
+    WheelRotations,
     Deg,
     Rad,
+    Rotations,
+    S,
+    Ms,
+    Min,
+    Percent,
+    N,
+    Hz,
+    KHz,
+    Dps,
+    Rpm,
+    CmPerS,
+}
+
+impl NumberSuffix {
+    fn name(self) -> &'static str {
+        match self {
+            NumberSuffix::Cm => "centimeters",
+            NumberSuffix::Mm => "millimeters",
+            NumberSuffix::M => "meters",
+            NumberSuffix::In => "inches",
+            NumberSuffix::Ft => "feet",
+            NumberSuffix::WheelRotations => "wheel rotations",
+            NumberSuffix::Deg => "degrees",
+            NumberSuffix::Rad => "radians",
+            NumberSuffix::Rotations => "rotations",
+            NumberSuffix::S => "seconds",
+            NumberSuffix::Ms => "milliseconds",
+            NumberSuffix::Min => "minutes",
+            NumberSuffix::Percent => "percent",
+            NumberSuffix::N => "newtons",
+            NumberSuffix::Hz => "hertz",
+            NumberSuffix::KHz => "kilohertz",
+            NumberSuffix::Dps => "degrees per second",
+            NumberSuffix::Rpm => "rpm",
+            NumberSuffix::CmPerS => "centimeters per second",
+        }
+    }
+
+    fn dimension(self) -> Dimension {
+        match self {
+            NumberSuffix::Cm
+            | NumberSuffix::Mm
+            | NumberSuffix::M
+            | NumberSuffix::In
+            | NumberSuffix::Ft
+            | NumberSuffix::WheelRotations => Dimension::Length,
+            NumberSuffix::Deg | NumberSuffix::Rad | NumberSuffix::Rotations => Dimension::Angle,
+            NumberSuffix::S | NumberSuffix::Ms | NumberSuffix::Min => Dimension::Time,
+            NumberSuffix::Dps | NumberSuffix::Rpm | NumberSuffix::CmPerS => Dimension::Speed,
+            NumberSuffix::Percent => Dimension::Percent,
+            NumberSuffix::N => Dimension::Force,
+            NumberSuffix::Hz | NumberSuffix::KHz => Dimension::Frequency,
+        }
+    }
+
+    fn factor(self) -> Expr {
+        match self {
+            NumberSuffix::Mm => Expr::Number(0.1),
+            NumberSuffix::M => Expr::Number(100.0),
+            NumberSuffix::In => Expr::Number(2.54),
+            NumberSuffix::Ft => Expr::Number(30.48),
+            NumberSuffix::WheelRotations => Expr::Global("CIRC"),
+            NumberSuffix::Rad => Expr::Number(180.0 / PI),
+            NumberSuffix::Rotations => Expr::Number(360.0),
+            NumberSuffix::S => Expr::Number(1000.0),
+            NumberSuffix::Min => Expr::Number(60000.0),
+            NumberSuffix::Rpm => Expr::Number(6.0),
+            NumberSuffix::KHz => Expr::Number(1000.0),
+            NumberSuffix::CmPerS => Expr::Binary(
+                Box::new(Expr::Number(360.0)),
+                BinOp::Div,
+                Box::new(Expr::Global("CIRC")),
+            ),
+            NumberSuffix::Cm
+            | NumberSuffix::Deg
+            | NumberSuffix::Ms
+            | NumberSuffix::Percent
+            | NumberSuffix::N
+            | NumberSuffix::Hz
+            | NumberSuffix::Dps => Expr::Number(1.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Code, Command, Diagnostic, Dimension, Expr, Port, Program, Quantity, Sources, Statement,
+    };
+
+    fn parse(code: &str) -> (Program, Vec<Diagnostic>) {
+        let mut sources = Sources::new();
+        sources.add("test.rbs", code.to_string());
+        crate::parse(&mut sources)
+    }
+
+    fn messages(diagnostics: &[Diagnostic]) -> Vec<&str> {
+        diagnostics.iter().map(|d| d.message.as_str()).collect()
+    }
+
+    #[test]
+    fn config_port_without_port_word() {
+        let (program, diagnostics) = parse("config\n  right motor B\n  left motor port A\nend\n");
+        assert!(diagnostics.is_empty(), "{:?}", messages(&diagnostics));
+        assert!(matches!(program.config.right_motor, Some(Port::B)));
+        assert!(matches!(program.config.left_motor, Some(Port::A)));
+    }
+
+    #[test]
+    fn config_port_used_twice() {
+        let (program, diagnostics) = parse("config\n  back arm F\n  force sensor F\nend\n");
+        assert_eq!(
+            messages(&diagnostics),
+            ["\"force sensor\" cannot use port F, \"back arm\" already uses it"]
+        );
+        assert!(program.config.force_sensor.is_none());
+    }
+
+    #[test]
+    fn config_value_without_unit_before_next_key() {
+        let (program, diagnostics) = parse("config\n  track width 12\n  max speed 1000\nend\n");
+        assert!(diagnostics.is_empty(), "{:?}", messages(&diagnostics));
+        assert_eq!(program.config.track_width, Some(12.0));
+        assert_eq!(program.config.max_speed, Some(1000.0));
+    }
+
+    fn parse_on_small_stack(code: String) -> Vec<Diagnostic> {
+        std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || parse(&code).1)
+            .unwrap()
+            .join()
+            .unwrap()
+    }
+
+    #[test]
+    fn long_file_without_stack_growth() {
+        let code = "# comment\n\nwait 1\n".repeat(100_000 / 3);
+        let diagnostics = parse_on_small_stack(code);
+        assert!(diagnostics.is_empty(), "{:?}", messages(&diagnostics));
+    }
+
+    #[test]
+    fn many_bad_lines_without_stack_growth() {
+        let diagnostics = parse_on_small_stack("5\n".repeat(100_000));
+        assert_eq!(diagnostics.len(), 100_000);
+        assert!(diagnostics.iter().all(|d| d.code == Code::UnexpectedToken));
+    }
+
+    #[test]
+    fn unexpected_tokens() {
+        let (_, diagnostics) =
+            parse("config\n  track width 12 cm x\nend\nred\n\"text\"\n)\n5\ntimes\n");
+        assert_eq!(diagnostics.len(), 6, "{:?}", messages(&diagnostics));
+        assert!(diagnostics.iter().all(|d| d.code == Code::UnexpectedToken));
+    }
+
+    #[test]
+    fn unit_per_operand() {
+        let (program, diagnostics) = parse(
+            "let d = 30 cm\nmove forward d + 5 cm\nmove forward d + (5 cm)\nwait (1 + 2) s - 500 ms\n",
+        );
+        assert!(diagnostics.is_empty(), "{:?}", messages(&diagnostics));
+        assert!(matches!(
+            program.script[3].node,
+            Statement::Command(Command::Wait(Quantity {
+                value: Expr::Number(2500.0),
+                ..
+            }))
+        ));
+
+        let (program, diagnostics) =
+            parse("define f(size)\n  move forward size + 5 cm\nend\nf(2 m)\nf(1 s)\n");
+        assert_eq!(
+            messages(&diagnostics),
+            ["argument 1 of \"f\" expects a length, found a time"]
+        );
+        assert_eq!(
+            program.procedures[0].params[0].dimension,
+            Some(Dimension::Length)
+        );
+        let Statement::Call(_, args) = &program.script[0].node else {
+            panic!("expected a call");
+        };
+        assert!(matches!(args[..], [Expr::Number(200.0)]));
+    }
+
+    #[test]
+    fn unary_plus() {
+        let (program, diagnostics) = parse("move forward +5\nwait 2 * +3 s\n");
+        assert!(diagnostics.is_empty(), "{:?}", messages(&diagnostics));
+        assert!(matches!(
+            program.script[0].node,
+            Statement::Command(Command::MoveForward {
+                amount: Quantity {
+                    value: Expr::Number(5.0),
+                    ..
+                },
+                ..
+            })
+        ));
+        assert!(matches!(
+            program.script[1].node,
+            Statement::Command(Command::Wait(Quantity {
+                value: Expr::Number(6000.0),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn value_error_reported_once() {
+        let (_, diagnostics) = parse("move forward 90 deg\nset speed 2 s\nwait 2 +\n");
+        assert_eq!(
+            messages(&diagnostics),
+            [
+                "expected a length, found an angle",
+                "expected a speed, found a time",
+                "expected a number, variable or \"(\""
+            ]
+        );
+    }
+
+    #[test]
+    fn recursive_call_with_arguments() {
+        let (_, diagnostics) = parse("define rec(x)\n  rec(x)\nend\n");
+        assert_eq!(messages(&diagnostics), ["recursive call: rec -> rec"]);
+        assert_eq!(diagnostics[0].code, Code::Recursion);
+    }
+
+    #[test]
+    fn argument_errors_point_at_the_call() {
+        let code = "f(1)\ndefine f(a b)\n  wait a\nend\nf(2)\nf(3 cm, 4)\n";
+        let (_, diagnostics) = parse(code);
+        assert_eq!(
+            messages(&diagnostics),
+            [
+                "\"f\" expects 2 arguments, got 1",
+                "argument 1 of \"f\" expects a time, found a length",
+                "\"f\" expects 2 arguments, got 1"
+            ]
+        );
+        for (diagnostic, call) in diagnostics.iter().zip(["f(2)", "f(3 cm", "f(1)"]) {
+            assert_eq!(diagnostic.span.start, code.find(call).unwrap());
+            assert_eq!(diagnostic.labels[0].message, "defined here");
+        }
+    }
+
+    #[test]
+    fn comma_separated_arguments() {
+        let (program, diagnostics) =
+            parse("define f(a, b)\n  move forward a\n  wait b\nend\nf(1, -2)\nf(3 +4, 5)\n");
+        assert!(diagnostics.is_empty(), "{:?}", messages(&diagnostics));
+        let Statement::Call(_, args) = &program.script[0].node else {
+            panic!("expected a call");
+        };
+        assert!(matches!(
+            args[..],
+            [Expr::Number(1.0), Expr::Number(-2000.0)]
+        ));
+        let Statement::Call(_, args) = &program.script[1].node else {
+            panic!("expected a call");
+        };
+        assert!(matches!(
+            args[..],
+            [Expr::Number(7.0), Expr::Number(5000.0)]
+        ));
+    }
+
+    #[test]
+    fn forward_call_with_arguments() {
+        let (program, diagnostics) =
+            parse("run(2 s)\ndefine run(t)\n  wait t\nend\nrun(1, 2)\nrun(3 cm)\n");
+        assert_eq!(
+            messages(&diagnostics),
+            [
+                "\"run\" expects 1 argument, got 2",
+                "argument 1 of \"run\" expects a time, found a length"
+            ]
+        );
+        let Statement::Call(_, args) = &program.script[0].node else {
+            panic!("expected a call");
+        };
+        assert!(matches!(args[..], [Expr::Number(2000.0)]));
+    }
 }
@@ -0,0 +1,298 @@
+use std::{
+    collections::HashSet,
+    fs, io, mem,
+    path::{Component, Path, PathBuf},
+};
+
+use peek_again::{Peekable, PeekableIterator};
+
+use crate::{
+    Code, Diagnostic, Span, Spanned,
+    keywords::IntToken,
+    lexer::{Lexer, Token},
+};
+
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub path: PathBuf,
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    files: Vec<Source>,
+}
+
+impl Sources {
+    pub fn new() -> Self {
+        Sources::default()
+    }
+
+    pub fn add(&mut self, path: impl AsRef<Path>, code: String) -> usize {
+        let path = normalize(path.as_ref());
+        if let Some(id) = self.find(&path) {
+            self.files[id].code = code;
+            id
+        } else {
+            self.files.push(Source { path, code });
+            self.files.len() - 1
+        }
+    }
+
+    pub fn load(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let code = fs::read_to_string(path.as_ref())?;
+        Ok(self.add(path, code))
+    }
+
+    pub fn get(&self, id: usize) -> &Source {
+        &self.files[id]
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    fn find(&self, path: &Path) -> Option<usize> {
+        self.files.iter().position(|file| file.path == path)
+    }
+
+    pub(crate) fn resolve_includes(&mut self) -> Vec<Diagnostic> {
+        let mut errors = Vec::new();
+        let mut i = 0;
+        while i < self.files.len() {
+            let mut lexer = Lexer::new(i, &self.files[i].code).peek_again();
+            let mut includes = Vec::new();
+            while let Some(token) = lexer.next() {
+                if let Token::Int(IntToken::Include) = token.node
+                    && let Some(Spanned {
+                        node: Token::Str(include),
+                        span,
+                    }) = lexer.peek().get()
+                {
+                    includes.push((include.clone(), *span));
+                }
+            }
+
+            let from = self.files[i].path.clone();
+            for (include, span) in includes {
+                let path = include_path(&from, &include);
+                if self.find(&path).is_some() {
+                    continue;
+                }
+                if let Err(err) = self.load(&path) {
+                    errors.push(Diagnostic::error(
+                        Code::Include,
+                        span,
+                        format!("cannot include \"{include}\": {err}"),
+                    ));
+                }
+            }
+            i += 1;
+        }
+        errors
+    }
+}
+
+fn include_path(from: &Path, include: &str) -> PathBuf {
+    normalize(&from.parent().unwrap_or(Path::new("")).join(include))
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+pub struct Tokens<'src> {
+    sources: &'src Sources,
+    stack: Vec<(usize, Peekable<Lexer<'src>>)>,
+    included: HashSet<usize>,
+    errors: Vec<Diagnostic>,
+    end: Span,
+}
+
+impl<'src> Tokens<'src> {
+    pub fn new(sources: &'src Sources) -> Self {
+        let mut tokens = Tokens {
+            sources,
+            stack: Vec::new(),
+            included: HashSet::new(),
+            errors: Vec::new(),
+            end: Span::default(),
+        };
+        if !sources.is_empty() {
+            tokens.included.insert(0);
+            tokens
+                .stack
+                .push((0, Lexer::new(0, &sources.get(0).code).peek_again()));
+        }
+        tokens
+    }
+
+    fn include(&mut self, from: usize, include: &str, span: Span) {
+        let path = include_path(&self.sources.get(from).path, include);
+        let Some(id) = self.sources.find(&path) else {
+            return;
+        };
+
+        if let Some(start) = self.stack.iter().position(|(file, _)| *file == id) {
+            let mut cycle = self.stack[start..]
+                .iter()
+                .map(|(file, _)| self.sources.get(*file).path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            cycle += " -> ";
+            cycle += &path.display().to_string();
+            self.errors.push(Diagnostic::error(
+                Code::Include,
+                span,
+                format!("include cycle: {cycle}"),
+            ));
+            return;
+        }
+
+        if self.included.insert(id) {
+            self.stack
+                .push((id, Lexer::new(id, &self.sources.get(id).code).peek_again()));
+        }
+    }
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Spanned<Token>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some((file, lexer)) = self.stack.last_mut() else {
+                if self.errors.is_empty() {
+                    return None;
+                } else {
+                    return Some(Spanned {
+                        node: Token::Errors(mem::take(&mut self.errors)),
+                        span: self.end,
+                    });
+                }
+            };
+            let file = *file;
+
+            let Some(token) = lexer.next() else {
+                self.stack.pop();
+                continue;
+            };
+            self.end = Span {
+                start: token.span.end,
+                ..token.span
+            };
+            match token.node {
+                Token::Errors(mut errors) => self.errors.append(&mut errors),
+                Token::Int(IntToken::Include) => match lexer.peek().get() {
+                    Some(Spanned {
+                        node: Token::Str(include),
+                        span,
+                    }) => {
+                        let (include, span) = (include.clone(), *span);
+                        lexer.next();
+                        self.include(file, &include, span);
+                    }
+                    _ => self.errors.push(Diagnostic::error(
+                        Code::ExpectedToken,
+                        token.span,
+                        "expected file name in quotes after \"include\"",
+                    )),
+                },
+                _ => return Some(token),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Code, Diagnostic, Program, Sources, Statement};
+
+    fn parse(files: &[(&str, &str)]) -> (Program, Vec<Diagnostic>) {
+        let mut sources = Sources::new();
+        for (path, code) in files {
+            sources.add(path, code.to_string());
+        }
+        crate::parse(&mut sources)
+    }
+
+    #[test]
+    fn relative_include() {
+        let (program, diagnostics) = parse(&[
+            ("robot/main.rbs", "include \"lib/moves.rbs\"\nforward\n"),
+            (
+                "robot/lib/moves.rbs",
+                "include \"../common.rbs\"\ndefine forward\n  wait 1\nend\n",
+            ),
+            ("robot/common.rbs", "clear display\n"),
+        ]);
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+        assert_eq!(program.procedures[0].name, "forward");
+        assert_eq!(program.script.len(), 2);
+    }
+
+    #[test]
+    fn diamond_include_is_spliced_once() {
+        let (program, diagnostics) = parse(&[
+            ("main.rbs", "include \"b.rbs\"\ninclude \"c.rbs\"\n"),
+            ("b.rbs", "include \"d.rbs\"\nwait 1\n"),
+            ("c.rbs", "include \"d.rbs\"\nwait 2\n"),
+            ("d.rbs", "define shared\n  wait 3\nend\nclear display\n"),
+        ]);
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+        assert_eq!(program.procedures.len(), 1);
+        assert_eq!(program.script.len(), 3);
+        assert!(matches!(program.script[0].node, Statement::Command(_)));
+    }
+
+    #[test]
+    fn include_cycle() {
+        let (_, diagnostics) = parse(&[
+            ("a.rbs", "include \"b.rbs\"\n"),
+            ("b.rbs", "include \"a.rbs\"\n"),
+        ]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Code::Include);
+        assert_eq!(
+            diagnostics[0].message,
+            "include cycle: a.rbs -> b.rbs -> a.rbs"
+        );
+    }
+
+    #[test]
+    fn missing_include() {
+        let code = "wait 1\ninclude \"no such file.rbs\"\n";
+        let (_, diagnostics) = parse(&[("missing/main.rbs", code)]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Code::Include);
+        assert!(
+            diagnostics[0]
+                .message
+                .starts_with("cannot include \"no such file.rbs\": "),
+            "{}",
+            diagnostics[0].message
+        );
+        let span = diagnostics[0].span;
+        assert_eq!(&code[span.start..span.end], "\"no such file.rbs\"");
+        assert_eq!((span.line, span.col), (2, 9));
+    }
+}